@@ -0,0 +1,28 @@
+CREATE TABLE IF NOT EXISTS codes (
+    code TEXT PRIMARY KEY,
+    grant_data TEXT NOT NULL,
+    redeemed INTEGER NOT NULL,
+    expires_at BIGINT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS sessions (
+    access_token TEXT PRIMARY KEY,
+    session_data TEXT NOT NULL,
+    expires_at BIGINT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS refresh_tokens (
+    refresh_token TEXT PRIMARY KEY,
+    grant_data TEXT NOT NULL,
+    expires_at BIGINT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS nonces (
+    nonce TEXT PRIMARY KEY,
+    expires_at BIGINT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS codes_expires_at ON codes (expires_at);
+CREATE INDEX IF NOT EXISTS sessions_expires_at ON sessions (expires_at);
+CREATE INDEX IF NOT EXISTS refresh_tokens_expires_at ON refresh_tokens (expires_at);
+CREATE INDEX IF NOT EXISTS nonces_expires_at ON nonces (expires_at);
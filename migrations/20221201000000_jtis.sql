@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS jtis (
+    jti TEXT PRIMARY KEY,
+    expires_at BIGINT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS jtis_expires_at ON jtis (expires_at);
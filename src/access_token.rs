@@ -0,0 +1,226 @@
+use chrono::Utc;
+use openidconnect::{AccessToken, PrivateSigningKey};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::claims::Claims;
+use crate::config::{get_client, Config, SCOPES};
+use crate::jose::encode;
+use crate::keys::Keys;
+use crate::token::AccessGrant;
+
+/// The claims of a JWT access token as in RFC 9068 section 2.2, extended by the NFT claims.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct AccessTokenClaims {
+    pub iss: String,
+    pub sub: String,
+    pub aud: String,
+    pub client_id: String,
+    pub scope: String,
+    pub exp: i64,
+    pub iat: i64,
+    pub jti: String,
+    pub contract: String,
+    pub chain_id: i32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token_id: Option<String>,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub balance: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub satisfied: Vec<String>,
+}
+
+/// Issues an access token for the client of the grant.
+///
+/// Clients with `jwt_access_token` get a JWT signed like the id tokens of the realm, which can be
+/// validated against the `/jwk` endpoint. All other clients get a random token.
+///
+/// The JWT expires with the grant and is meant for the client's `access_token_audience`, or for
+/// the realm itself when none is configured. Revoking the grant does not invalidate a JWT that
+/// was already issued; resource servers that validate it offline accept it until `exp`.
+pub fn issue_access_token(
+    config: &Config,
+    keys: &Keys,
+    grant: &AccessGrant,
+    claims: &Claims,
+) -> AccessToken {
+    let client = match get_client(config, &grant.client_id) {
+        Some(client) if client.jwt_access_token => client,
+        _ => return AccessToken::new(Uuid::new_v4().to_string()),
+    };
+
+    let signing_key = keys.for_realm(config, &grant.realm);
+    let alg = signing_key.key.alg();
+    let header = serde_json::json!({
+        "typ": "at+jwt",
+        "alg": alg,
+        "kid": signing_key.kid,
+    });
+    let iss = format!("{}/{}", config.ext_hostname, grant.realm);
+    let claims = AccessTokenClaims {
+        aud: client
+            .access_token_audience
+            .clone()
+            .unwrap_or_else(|| iss.clone()),
+        iss,
+        sub: claims.account.clone(),
+        client_id: grant.client_id.clone(),
+        scope: SCOPES.join(" "),
+        exp: grant.expires_at.timestamp(),
+        iat: Utc::now().timestamp(),
+        jti: Uuid::new_v4().to_string(),
+        contract: claims.contract.clone(),
+        chain_id: claims.chain_id,
+        token_id: claims.token_id.clone(),
+        balance: claims.balance.clone(),
+        satisfied: claims.satisfied.clone(),
+    };
+    let message = format!(
+        "{}.{}",
+        encode(header.to_string().as_bytes()),
+        encode(&serde_json::to_vec(&claims).unwrap())
+    );
+    let signature = signing_key
+        .key
+        .sign(&alg, message.as_bytes())
+        .expect("access token signature");
+    AccessToken::new(format!("{}.{}", message, encode(&signature)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ClientConfig;
+    use crate::jose::{NftJsonWebKeySet, NftJwsSigningAlgorithm};
+    use crate::rocket;
+    use crate::tests::issue_code;
+    use crate::web3::OwnershipCondition;
+    use chrono::Duration;
+    use openidconnect::JsonWebKey;
+    use rocket::http::{ContentType, Header, Status};
+    use rocket::local::blocking::Client;
+    use serde_json::Value;
+
+    fn decode(segment: &str) -> Vec<u8> {
+        base64::decode_config(segment, base64::URL_SAFE_NO_PAD).unwrap()
+    }
+
+    #[test]
+    fn jwt_access_token() {
+        let client = Client::tracked(rocket()).expect("valid rocket instance");
+        let code = issue_code(&client, "api", "https://example.com");
+        let response = client
+            .post("/okt/token")
+            .header(ContentType::Form)
+            .body(format!(
+                "grant_type=authorization_code&code={}&redirect_uri=https://example.com&client_id=api",
+                code
+            ))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let token = response.into_json::<Value>().unwrap();
+        let access_token = token.get("access_token").unwrap().as_str().unwrap();
+
+        let segments: Vec<&str> = access_token.split('.').collect();
+        assert_eq!(segments.len(), 3);
+        let header: Value = serde_json::from_slice(&decode(segments[0])).unwrap();
+        assert_eq!(header.get("typ").unwrap(), "at+jwt");
+
+        let alg: NftJwsSigningAlgorithm =
+            serde_json::from_value(header.get("alg").unwrap().clone()).unwrap();
+        let keys: NftJsonWebKeySet = client.get("/okt/jwk").dispatch().into_json().unwrap();
+        let key = keys
+            .keys()
+            .iter()
+            .find(|key| key.key_id().unwrap().as_str() == header.get("kid").unwrap())
+            .unwrap();
+        assert!(key
+            .verify_signature(
+                &alg,
+                format!("{}.{}", segments[0], segments[1]).as_bytes(),
+                &decode(segments[2]),
+            )
+            .is_ok());
+
+        let claims: AccessTokenClaims = serde_json::from_slice(&decode(segments[1])).unwrap();
+        assert_eq!(claims.iss, "http://localhost:8000/okt");
+        assert_eq!(claims.sub, "0x9c9e8eabd947658bdb713e0d3ebfe56860abdb8d");
+        assert_eq!(claims.aud, "https://api.example.com");
+        assert_eq!(claims.client_id, "api");
+        assert_eq!(claims.scope, "openid email");
+        assert_eq!(
+            claims.contract,
+            "0x886B6781CD7dF75d8440Aba84216b2671AEFf9A4"
+        );
+        assert_eq!(claims.chain_id, 65);
+        assert!(claims.exp > claims.iat);
+
+        let response = client
+            .get("/okt/userinfo")
+            .header(Header::new(
+                "Authorization",
+                format!("Bearer {}", access_token),
+            ))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+    }
+
+    fn claims() -> Claims {
+        crate::claims::additional_claims(
+            &"0x9c9e8eabd947658bdb713e0d3ebfe56860abdb8d".to_string(),
+            &"nonce".to_string(),
+            &"".to_string(),
+            &65,
+            &"".to_string(),
+            &"0x886B6781CD7dF75d8440Aba84216b2671AEFf9A4".to_string(),
+            &None,
+            &"".to_string(),
+            &[],
+        )
+    }
+
+    fn grant(client_id: &str) -> AccessGrant {
+        AccessGrant {
+            client_id: client_id.to_string(),
+            realm: "okt".to_string(),
+            chain: "okt".to_string(),
+            condition: OwnershipCondition::new(
+                "0x886B6781CD7dF75d8440Aba84216b2671AEFf9A4".to_string(),
+            ),
+            expires_at: Utc::now() + Duration::minutes(5),
+        }
+    }
+
+    #[test]
+    fn random_access_token() {
+        let client = Client::tracked(rocket()).expect("valid rocket instance");
+        let config = client.rocket().state::<Config>().unwrap();
+        let keys = client.rocket().state::<Keys>().unwrap();
+        let access_token = issue_access_token(config, keys, &grant("foo"), &claims());
+        assert!(Uuid::parse_str(access_token.secret()).is_ok());
+    }
+
+    #[test]
+    fn jwt_access_token_of_grant() {
+        let client = Client::tracked(rocket()).expect("valid rocket instance");
+        let keys = client.rocket().state::<Keys>().unwrap();
+        let mut config = Config {
+            ext_hostname: "http://localhost:8000".to_string(),
+            ..Default::default()
+        };
+        config.clients.insert(
+            "api".into(),
+            ClientConfig {
+                jwt_access_token: true,
+                ..Default::default()
+            },
+        );
+        let grant = grant("api");
+
+        let access_token = issue_access_token(&config, keys, &grant, &claims());
+        let segments: Vec<&str> = access_token.secret().split('.').collect();
+        let claims: AccessTokenClaims = serde_json::from_slice(&decode(segments[1])).unwrap();
+        assert_eq!(claims.aud, "http://localhost:8000/okt");
+        assert_eq!(claims.exp, grant.expires_at.timestamp());
+    }
+}
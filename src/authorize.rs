@@ -1,21 +1,38 @@
-use crate::claims::{additional_claims, standard_claims, ClaimsMutex};
-use crate::config::{get_chain_id, get_node, Config};
-use crate::token::{token, Tokens};
-use crate::web3::{is_nft_owner_of, validate_signature};
-use openidconnect::{AccessToken, AuthorizationCode, TokenResponse};
+use crate::access_token::issue_access_token;
+use crate::claims::{additional_claims, standard_claims};
+use crate::config::{
+    allows_legacy_signature, get_chain_id, get_client, get_domain, get_node,
+    is_supported_response_type, ClientConfig, Config,
+};
+use crate::eip712::{validate_typed_data, LoginTypedData};
+use crate::jwe::encrypt_id_token;
+use crate::keys::Keys;
+use crate::nonce::{consume_nonce, issue_nonce};
+use crate::pkce::validate_code_challenge;
+use crate::policy::verify_access;
+use crate::refresh::issue_refresh_token;
+use crate::siwe::validate_siwe_message;
+use crate::storage::{Store, STORAGE_FULL};
+use crate::token::{access_ttl, store_token, token, AccessGrant, Grant};
+use crate::web3::{
+    eth_message, parse_token_ids, validate_signature, OwnershipCondition, TokenStandard,
+};
+use chrono::{Duration, Utc};
+use openidconnect::{AuthorizationCode, TokenResponse};
 use rocket::http::Status;
 use rocket::response::Redirect;
 use rocket::State;
+use std::str::FromStr;
 use url::Url;
 use uuid::Uuid;
 
 #[get(
-    "/<realm>/authorize?<client_id>&<redirect_uri>&<state>&<response_type>&<response_mode>&<nonce>&<account>&<signature>&<chain_id>&<contract>"
+    "/<realm>/authorize?<client_id>&<redirect_uri>&<state>&<response_type>&<response_mode>&<nonce>&<account>&<signature>&<chain_id>&<contract>&<message>&<challenge>&<expiry>&<token_standard>&<token_ids>&<token_id>&<min_balance>&<code_challenge>&<code_challenge_method>"
 )]
 pub async fn authorize_endpoint(
     config: &State<Config>,
-    claims: &State<ClaimsMutex>,
-    tokens: &State<Tokens>,
+    keys: &State<Keys>,
+    store: &State<Store>,
     realm: String,
     client_id: String,
     redirect_uri: String,
@@ -27,20 +44,129 @@ pub async fn authorize_endpoint(
     signature: Option<String>,
     chain_id: Option<String>,
     contract: Option<String>,
+    message: Option<String>,
+    challenge: Option<String>,
+    expiry: Option<u64>,
+    token_standard: Option<String>,
+    token_ids: Option<String>,
+    token_id: Option<String>,
+    min_balance: Option<String>,
+    code_challenge: Option<String>,
+    code_challenge_method: Option<String>,
 ) -> Result<Redirect, (Status, String)> {
+    let client = match get_client(config, &client_id) {
+        Some(client) => client,
+        None => return Err((Status::BadRequest, "unknown client".to_string())),
+    };
+
+    let mut redirect_uri = match Url::parse(&redirect_uri) {
+        Ok(redirect_uri) if client.allows_redirect_uri(&redirect_uri) => redirect_uri,
+        _ => return Err((Status::BadRequest, "wrong redirect uri".to_string())),
+    };
+
+    let response_type = response_type
+        .filter(|response_type| !response_type.is_empty())
+        .unwrap_or_else(|| "code".to_string());
+
+    if !is_supported_response_type(&response_type) {
+        return Ok(error_redirect(
+            redirect_uri,
+            "unsupported_response_type",
+            "response type not supported",
+            state,
+        ));
+    }
+
+    if !client.allows_response_type(&response_type) {
+        return Ok(error_redirect(
+            redirect_uri,
+            "unauthorized_client",
+            "response type not allowed for client",
+            state,
+        ));
+    }
+
+    let realm_or_chain_id = match realm.as_str() {
+        "default" => chain_id.clone().unwrap_or("default".into()),
+        _ => realm.clone(),
+    };
+
+    if !client.allows_realm(&realm_or_chain_id) {
+        return Ok(error_redirect(
+            redirect_uri,
+            "unauthorized_client",
+            "realm not allowed for client",
+            state,
+        ));
+    }
+
+    let code_challenge_method = match &code_challenge {
+        Some(code_challenge) => {
+            match validate_code_challenge(code_challenge, code_challenge_method.as_deref()) {
+                Ok(code_challenge_method) => Some(code_challenge_method),
+                Err(err) => {
+                    return Ok(error_redirect(redirect_uri, "invalid_request", &err, state))
+                }
+            }
+        }
+        None if client.require_pkce => {
+            return Ok(error_redirect(
+                redirect_uri,
+                "invalid_request",
+                "code challenge required",
+                state,
+            ))
+        }
+        None => None,
+    };
+
+    let contract = client
+        .contract
+        .clone()
+        .or(contract)
+        .unwrap_or(client_id.clone());
+
     if account.is_none() {
+        let login_challenge = issue_nonce(store, config.nonce_ttl.unwrap_or(300))
+            .await
+            .map_err(|err| match err.as_str() {
+                STORAGE_FULL => (Status::ServiceUnavailable, err),
+                _ => (Status::InternalServerError, err),
+            })?;
         let mut url = Url::parse(&format!("{}/{}", config.ext_hostname, realm)).unwrap();
         url.query_pairs_mut()
             .clear()
             .append_pair("client_id", &client_id)
             .append_pair("state", &state.unwrap_or_default())
             .append_pair("nonce", &nonce.unwrap_or_default())
-            .append_pair("response_type", &response_type.unwrap_or_default())
+            .append_pair("response_type", &response_type)
             .append_pair("response_mode", &response_mode.unwrap_or_default())
-            .append_pair("redirect_uri", &redirect_uri)
+            .append_pair("redirect_uri", redirect_uri.as_str())
             .append_pair("realm", &realm.clone())
             .append_pair("chain_id", &chain_id.clone().unwrap_or(realm.clone()))
-            .append_pair("contract", &contract.unwrap_or(client_id.clone()));
+            .append_pair("contract", &contract)
+            .append_pair("challenge", &login_challenge);
+        if let Some(token_standard) = token_standard {
+            url.query_pairs_mut()
+                .append_pair("token_standard", &token_standard);
+        }
+        if let Some(token_ids) = token_ids {
+            url.query_pairs_mut().append_pair("token_ids", &token_ids);
+        }
+        if let Some(token_id) = token_id {
+            url.query_pairs_mut().append_pair("token_id", &token_id);
+        }
+        if let Some(min_balance) = min_balance {
+            url.query_pairs_mut()
+                .append_pair("min_balance", &min_balance);
+        }
+        if let (Some(code_challenge), Some(code_challenge_method)) =
+            (&code_challenge, &code_challenge_method)
+        {
+            url.query_pairs_mut()
+                .append_pair("code_challenge", code_challenge)
+                .append_pair("code_challenge_method", code_challenge_method);
+        }
         return Ok(Redirect::temporary(url.to_string()));
     };
 
@@ -52,46 +178,99 @@ pub async fn authorize_endpoint(
         return Err((Status::BadRequest, "signature missing".to_string()));
     }
 
-    let redirect_uri = Url::parse(&redirect_uri);
-
-    if redirect_uri.is_err() {
-        return Err((Status::BadRequest, "wrong redirect uri".to_string()));
-    }
-
-    let mut redirect_uri = redirect_uri.unwrap();
-
-    if !validate_signature(
-        account.clone().unwrap(),
-        nonce.clone().unwrap(),
-        signature.clone().unwrap(),
-    ) {
-        return Err((Status::BadRequest, "no valide signature".to_string()));
+    if challenge.is_none() {
+        return Err((Status::BadRequest, "challenge missing".to_string()));
     }
 
-    let realm_or_chain_id = match realm.as_str() {
-        "default" => chain_id.clone().unwrap_or("default".into()),
-        _ => realm.clone(),
-    };
+    let condition = ownership_condition(
+        client,
+        contract.clone(),
+        token_standard,
+        token_ids,
+        token_id,
+        min_balance,
+    )?;
 
     let node_provider = get_node(config, &realm_or_chain_id);
-    let contract = contract.unwrap_or(client_id.clone());
-
-    let is_owner = is_nft_owner_of(
-        contract.clone(),
-        account.clone().unwrap_or_default(),
-        node_provider.clone(),
-    )
-    .await;
 
-    if is_owner.is_ok() {
-        if !is_owner.unwrap() {
-            return Err((Status::Unauthorized, "account is no owner".to_string()));
+    match (message, expiry) {
+        (Some(message), _) => {
+            if let Err(err) = validate_siwe_message(
+                &message,
+                signature.as_ref().unwrap(),
+                &get_domain(config),
+                &config.ext_hostname,
+                &account.clone().unwrap_or_default(),
+                get_chain_id(config, &realm_or_chain_id),
+                challenge.as_ref().unwrap(),
+                &node_provider,
+            )
+            .await
+            {
+                return Err((Status::BadRequest, err));
+            }
         }
-    } else {
-        return Err((Status::Unauthorized, "account is no owner".to_string()));
+        (None, Some(expiry)) => {
+            let typed_data = LoginTypedData::new(
+                &get_domain(config),
+                get_chain_id(config, &realm_or_chain_id),
+                &account.clone().unwrap_or_default(),
+                challenge.as_ref().unwrap(),
+                &client_id,
+                &contract,
+                expiry,
+            )
+            .map_err(|err| (Status::BadRequest, err))?;
+            if let Err(err) = validate_typed_data(
+                &typed_data,
+                signature.as_ref().unwrap(),
+                &node_provider,
+                config.nonce_ttl.unwrap_or(300),
+            )
+            .await
+            {
+                return Err((Status::BadRequest, err));
+            }
+        }
+        (None, None) => {
+            if !allows_legacy_signature(config, &realm) {
+                return Err((Status::BadRequest, "message missing".to_string()));
+            }
+            if !validate_signature(
+                account.clone().unwrap(),
+                eth_message(format!(
+                    "{};{}",
+                    &account.clone().unwrap_or_default(),
+                    challenge.as_ref().unwrap()
+                )),
+                signature.clone().unwrap(),
+                node_provider.clone(),
+            )
+            .await
+            {
+                return Err((Status::BadRequest, "no valide signature".to_string()));
+            }
+        }
+    }
+
+    if let Err(err) = consume_nonce(store, challenge.as_ref().unwrap()).await {
+        return Err((Status::BadRequest, err));
     }
 
-    let access_token = AccessToken::new(Uuid::new_v4().to_string());
+    let access = match verify_access(
+        config,
+        client,
+        &condition,
+        &realm_or_chain_id,
+        &account.clone().unwrap_or_default(),
+        &node_provider,
+    )
+    .await
+    {
+        Ok(access) if access.is_owner => access,
+        _ => return Err((Status::Unauthorized, "account is no owner".to_string())),
+    };
+
     let code = AuthorizationCode::new(Uuid::new_v4().to_string());
     let chain_id = get_chain_id(config, &realm_or_chain_id);
 
@@ -104,84 +283,170 @@ pub async fn authorize_endpoint(
         &chain_id,
         &node_provider.clone(),
         &contract,
+        &condition.token_id.map(|token_id| token_id.to_string()),
+        &access.balance,
+        &access.satisfied,
     );
+    let access_grant = AccessGrant {
+        client_id: client_id.clone(),
+        realm: realm.clone(),
+        chain: realm_or_chain_id.clone(),
+        condition,
+        expires_at: Utc::now() + Duration::seconds(access_ttl(config)),
+    };
+    let access_token = issue_access_token(config, keys, &access_grant, &additional_claims);
 
-    claims
-        .standard_claims
-        .lock()
-        .unwrap()
-        .insert(access_token.secret().clone(), standard_claims.clone());
-    claims
-        .additional_claims
-        .lock()
-        .unwrap()
-        .insert(access_token.secret().clone(), additional_claims.clone());
-
-    let token = token(
+    let mut token = token(
         config,
-        realm,
-        client_id,
+        keys,
+        realm.clone(),
+        client_id.clone(),
         nonce,
-        standard_claims,
-        additional_claims,
+        standard_claims.clone(),
+        additional_claims.clone(),
         access_token.clone(),
-        code.clone(),
+        Some(code.clone()),
     )
     .await;
+    let refresh_token = issue_refresh_token(
+        config,
+        store,
+        &access_token,
+        &access_grant,
+        &standard_claims,
+        &additional_claims,
+    )
+    .await
+    .map_err(|err| (Status::InternalServerError, err))?;
+    token.set_refresh_token(Some(refresh_token));
 
-    println!("{:?}", access_token.secret());
-    println!("{:?}", code.secret());
-    let id_token = token.id_token().unwrap().to_string();
-
-    tokens
-        .bearer
-        .lock()
-        .unwrap()
-        .insert(code.secret().clone(), access_token.secret().clone());
-    tokens
-        .muted
-        .lock()
-        .unwrap()
-        .insert(access_token.secret().clone(), token);
-
-    if let Some(response_type) = response_type {
-        if response_type.contains("code") {
-            redirect_uri
-                .query_pairs_mut()
-                .append_pair("code", code.secret());
-        }
-        if response_type.contains("id_token") {
-            redirect_uri
-                .query_pairs_mut()
-                .append_pair("id_token", &id_token);
-        } else if response_type.contains("token") {
-            redirect_uri
-                .query_pairs_mut()
-                .append_pair("id_token", &id_token);
-        }
-    } else {
+    let id_token = encrypt_id_token(
+        get_client(config, &client_id),
+        token.id_token().unwrap().to_string(),
+    )
+    .map_err(|err| (Status::InternalServerError, err))?;
+
+    store
+        .insert_code(
+            code.secret(),
+            Grant {
+                client_id: client_id.clone(),
+                redirect_uri: redirect_uri.to_string(),
+                access_token: access_token.secret().clone(),
+                code_challenge,
+                code_challenge_method,
+                expires_at: Utc::now() + Duration::seconds(config.code_ttl.unwrap_or(60)),
+                redeemed: false,
+            },
+        )
+        .await
+        .map_err(|err| (Status::InternalServerError, err))?;
+    store_token(
+        store,
+        access_grant,
+        token,
+        standard_claims,
+        additional_claims,
+    )
+    .await
+    .map_err(|err| (Status::InternalServerError, err))?;
+
+    if response_type.contains("code") {
         redirect_uri
             .query_pairs_mut()
             .append_pair("code", code.secret());
+    }
+    if response_type.contains("id_token") || response_type.contains("token") {
+        redirect_uri
+            .query_pairs_mut()
+            .append_pair("id_token", &id_token);
+    }
+
+    if let Some(state) = state {
+        redirect_uri.query_pairs_mut().append_pair("state", &state);
+    }
+
+    Ok(Redirect::temporary(redirect_uri.to_string()))
+}
+
+/// Sends an OAuth error back to a registered redirect uri, as in RFC 6749 section 4.1.2.1.
+fn error_redirect(
+    mut redirect_uri: Url,
+    error: &str,
+    error_description: &str,
+    state: Option<String>,
+) -> Redirect {
+    redirect_uri
+        .query_pairs_mut()
+        .append_pair("error", error)
+        .append_pair("error_description", error_description);
+    if let Some(state) = state {
+        redirect_uri.query_pairs_mut().append_pair("state", &state);
+    }
+    Redirect::temporary(redirect_uri.to_string())
+}
+
+/// The ownership condition of an authorize request.
+///
+/// Settings configured on the client take precedence over the request. A client `token_id` pins
+/// the condition to that token, so the token ids and token standard of the request are ignored.
+fn ownership_condition(
+    client: &ClientConfig,
+    contract: String,
+    token_standard: Option<String>,
+    token_ids: Option<String>,
+    token_id: Option<String>,
+    min_balance: Option<String>,
+) -> Result<OwnershipCondition, (Status, String)> {
+    let (token_standard, token_ids, token_id) = match &client.token_id {
+        Some(token_id) => (None, None, Some(token_id.clone())),
+        None => (token_standard, token_ids, token_id),
     };
 
-    match state {
-        Some(state) => {
-            redirect_uri.query_pairs_mut().append_pair("state", &state);
+    let token_standard = match (client.token_standard, token_standard) {
+        (Some(token_standard), _) => token_standard,
+        (None, Some(token_standard)) => {
+            TokenStandard::from_str(&token_standard).map_err(|err| (Status::BadRequest, err))?
         }
-        _ => {}
+        (None, None) => TokenStandard::Erc721,
+    };
+
+    let token_ids = match token_ids {
+        Some(token_ids) => parse_token_ids(&token_ids).map_err(|err| (Status::BadRequest, err))?,
+        None => Vec::new(),
+    };
+
+    let token_id = match token_id {
+        Some(token_id) => Some(
+            parse_token_ids(&token_id)
+                .ok()
+                .filter(|token_ids| token_ids.len() == 1)
+                .map(|token_ids| token_ids[0])
+                .ok_or((Status::BadRequest, "invalid token id".to_string()))?,
+        ),
+        None => None,
+    };
+
+    if token_standard == TokenStandard::Erc1155 && token_ids.is_empty() && token_id.is_none() {
+        return Err((Status::BadRequest, "token_ids missing".to_string()));
     }
 
-    Ok(Redirect::temporary(redirect_uri.to_string()))
+    Ok(OwnershipCondition {
+        contract,
+        token_standard,
+        token_ids,
+        token_id,
+        min_balance: client.min_balance.clone().or(min_balance),
+    })
 }
 
 #[get(
-    "/authorize?<client_id>&<redirect_uri>&<state>&<response_type>&<response_mode>&<nonce>&<account>&<signature>&<realm>&<chain_id>&<contract>"
+    "/authorize?<client_id>&<redirect_uri>&<state>&<response_type>&<response_mode>&<nonce>&<account>&<signature>&<realm>&<chain_id>&<contract>&<message>&<challenge>&<expiry>&<token_standard>&<token_ids>&<token_id>&<min_balance>&<code_challenge>&<code_challenge_method>"
 )]
 pub async fn default_authorize_endpoint(
     config: &State<Config>,
-    claims: &State<ClaimsMutex>,
-    tokens: &State<Tokens>,
+    keys: &State<Keys>,
+    store: &State<Store>,
     realm: Option<String>,
     client_id: String,
     redirect_uri: String,
@@ -193,11 +458,20 @@ pub async fn default_authorize_endpoint(
     signature: Option<String>,
     chain_id: Option<String>,
     contract: Option<String>,
+    message: Option<String>,
+    challenge: Option<String>,
+    expiry: Option<u64>,
+    token_standard: Option<String>,
+    token_ids: Option<String>,
+    token_id: Option<String>,
+    min_balance: Option<String>,
+    code_challenge: Option<String>,
+    code_challenge_method: Option<String>,
 ) -> Result<Redirect, (Status, String)> {
     authorize_endpoint(
         config,
-        claims,
-        tokens,
+        keys,
+        store,
         realm.unwrap_or("default".into()),
         client_id,
         redirect_uri,
@@ -209,17 +483,80 @@ pub async fn default_authorize_endpoint(
         signature,
         chain_id,
         contract,
+        message,
+        challenge,
+        expiry,
+        token_standard,
+        token_ids,
+        token_id,
+        min_balance,
+        code_challenge,
+        code_challenge_method,
     )
     .await
 }
 
 #[cfg(test)]
 mod tests {
+    use super::ownership_condition;
+    use crate::config::ClientConfig;
+    use crate::eip712::LoginTypedData;
     use crate::rocket;
+    use crate::storage::Store;
+    use crate::tests::issue_challenge;
+    use crate::tests::wallet;
+    use crate::web3::TokenStandard;
+    use chrono::{Duration, Utc};
+    use futures::executor::block_on;
     use rocket::http::Status;
     use rocket::local::blocking::Client;
     use std::collections::HashMap;
     use url::Url;
+    use web3::types::U256;
+
+    fn urlencoding(value: &str) -> String {
+        url::form_urlencoded::byte_serialize(value.as_bytes()).collect()
+    }
+
+    #[test]
+    fn client_token_id_ignores_request_token_ids() {
+        let client = ClientConfig {
+            token_id: Some("42".into()),
+            ..Default::default()
+        };
+        let condition = ownership_condition(
+            &client,
+            "0xa0d4E5CdD89330ef9d0d1071247909882f0562eA".into(),
+            Some("erc1155".into()),
+            Some("1,2".into()),
+            Some("7".into()),
+            None,
+        )
+        .unwrap();
+        assert_eq!(condition.token_standard, TokenStandard::Erc721);
+        assert!(condition.token_ids.is_empty());
+        assert_eq!(condition.token_id, Some(U256::from(42)));
+    }
+
+    #[test]
+    fn client_settings_take_precedence() {
+        let client = ClientConfig {
+            token_standard: Some(TokenStandard::Erc20),
+            min_balance: Some("100".into()),
+            ..Default::default()
+        };
+        let condition = ownership_condition(
+            &client,
+            "0xa0d4E5CdD89330ef9d0d1071247909882f0562eA".into(),
+            Some("erc721".into()),
+            None,
+            None,
+            Some("0".into()),
+        )
+        .unwrap();
+        assert_eq!(condition.token_standard, TokenStandard::Erc20);
+        assert_eq!(condition.min_balance, Some("100".to_string()));
+    }
 
     #[test]
     fn redirect() {
@@ -227,7 +564,7 @@ mod tests {
         let client = Client::tracked(rocket()).expect("valid rocket instance");
         let response = client
             .get(format!(
-                "/authorize?client_id={}&realm=kovan&redirect_uri=unused",
+                "/authorize?client_id={}&realm=kovan&redirect_uri=http://localhost:3000/callback",
                 client_id
             ))
             .dispatch();
@@ -250,6 +587,64 @@ mod tests {
         assert_eq!(params.get("chain_id"), Some(&"kovan".to_string()));
 
         assert_eq!(params.get("contract"), Some(&client_id.to_string()));
+
+        assert!(params.contains_key("challenge"));
+    }
+
+    #[test]
+    fn challenge_not_issued() {
+        let key = wallet::key();
+        let account = wallet::address(&key);
+        let contract = "0x886B6781CD7dF75d8440Aba84216b2671AEFf9A4";
+        let challenge = "notissued";
+        let signature = wallet::sign(&key, &format!("{};{}", account, challenge));
+
+        let client = Client::tracked(rocket()).expect("valid rocket instance");
+
+        let response = client
+            .get(format!(
+                "/authorize?client_id=foo&realm=okt&redirect_uri=https://example.com&nonce=42&contract={}&account={}&signature={}&challenge={}",
+                contract, account, signature, challenge
+            ))
+            .dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+        assert_eq!(
+            response.into_string().unwrap(),
+            "challenge unknown or already used"
+        );
+    }
+
+    #[test]
+    fn challenge_replayed() {
+        let key = wallet::key();
+        let account = wallet::address(&key);
+        let contract = "0x886B6781CD7dF75d8440Aba84216b2671AEFf9A4";
+
+        let client = Client::tracked(rocket()).expect("valid rocket instance");
+
+        let response = client
+            .get("/authorize?client_id=foo&realm=okt&redirect_uri=https://example.com")
+            .dispatch();
+        let response_url = Url::parse(response.headers().get("Location").next().unwrap()).unwrap();
+        let (_, challenge) = response_url
+            .query_pairs()
+            .find(|(key, _)| key == "challenge")
+            .unwrap();
+        let signature = wallet::sign(&key, &format!("{};{}", account, challenge));
+        let uri = format!(
+            "/authorize?client_id=foo&realm=okt&redirect_uri=https://example.com&nonce=42&contract={}&account={}&signature={}&challenge={}",
+            contract, account, signature, challenge
+        );
+
+        let response = client.get(uri.clone()).dispatch();
+        assert_ne!(response.status(), Status::BadRequest);
+
+        let response = client.get(uri).dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+        assert_eq!(
+            response.into_string().unwrap(),
+            "challenge unknown or already used"
+        );
     }
 
     #[test]
@@ -264,14 +659,97 @@ mod tests {
 
         let response = client
             .get(format!(
-                "/authorize?client_id={}&realm=okt&redirect_uri=wrong_uri&nonce={}&contract={}&account={}&signature={}",
-                client_id, nonce, contract, account, signature
+                "/authorize?client_id={}&realm=okt&redirect_uri=wrong_uri&nonce={}&contract={}&account={}&signature={}&challenge={}",
+                client_id, nonce, contract, account, signature, nonce
             ))
             .dispatch();
         assert_eq!(response.status(), Status::BadRequest);
         assert_eq!(response.into_string().unwrap(), "wrong redirect uri");
     }
 
+    #[test]
+    fn unknown_client() {
+        let client = Client::tracked(rocket()).expect("valid rocket instance");
+        let response = client
+            .get("/authorize?client_id=unknown&realm=okt&redirect_uri=https://example.com")
+            .dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+        assert_eq!(response.into_string().unwrap(), "unknown client");
+    }
+
+    #[test]
+    fn unregistered_redirect_uri() {
+        let client = Client::tracked(rocket()).expect("valid rocket instance");
+        let response = client
+            .get("/authorize?client_id=foo&realm=okt&redirect_uri=https://evil.example.com")
+            .dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+        assert_eq!(response.into_string().unwrap(), "wrong redirect uri");
+    }
+
+    #[test]
+    fn unauthorized_client() {
+        let client = Client::tracked(rocket()).expect("valid rocket instance");
+        let error = |uri: &str| {
+            let response = client.get(uri.to_string()).dispatch();
+            assert_eq!(response.status(), Status::TemporaryRedirect);
+            let response_url =
+                Url::parse(response.headers().get("Location").next().unwrap()).unwrap();
+            assert_eq!(response_url.host_str(), Some("example.com"));
+            let params: HashMap<String, String> = response_url.query_pairs().into_owned().collect();
+            assert_eq!(params.get("state"), Some(&"state".to_string()));
+            params.get("error").cloned()
+        };
+
+        assert_eq!(
+            error(
+                "/authorize?client_id=bar&realm=kovan&redirect_uri=https://example.com&state=state"
+            ),
+            Some("unauthorized_client".to_string())
+        );
+        assert_eq!(
+            error("/authorize?client_id=bar&realm=okt&redirect_uri=https://example.com&state=state&response_type=id_token"),
+            Some("unauthorized_client".to_string())
+        );
+        assert_eq!(
+            error("/authorize?client_id=foo&realm=okt&redirect_uri=https://example.com&state=state&response_type=device"),
+            Some("unsupported_response_type".to_string())
+        );
+
+        let response = client
+            .get("/authorize?client_id=bar&realm=okt&redirect_uri=https://example.com")
+            .dispatch();
+        assert_eq!(response.status(), Status::TemporaryRedirect);
+        let response_url = Url::parse(response.headers().get("Location").next().unwrap()).unwrap();
+        assert_eq!(response_url.host_str(), Some("localhost"));
+    }
+
+    #[test]
+    fn pkce_code_challenge() {
+        let client = Client::tracked(rocket()).expect("valid rocket instance");
+        let challenge = "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM";
+
+        let response = client
+            .get(format!("/authorize?client_id=foo&realm=okt&redirect_uri=https://example.com&code_challenge={}&code_challenge_method=S256", challenge))
+            .dispatch();
+        assert_eq!(response.status(), Status::TemporaryRedirect);
+        let response_url = Url::parse(response.headers().get("Location").next().unwrap()).unwrap();
+        let params: HashMap<String, String> = response_url.query_pairs().into_owned().collect();
+        assert_eq!(params.get("code_challenge"), Some(&challenge.to_string()));
+        assert_eq!(
+            params.get("code_challenge_method"),
+            Some(&"S256".to_string())
+        );
+
+        let response = client
+            .get(format!("/authorize?client_id=foo&realm=okt&redirect_uri=https://example.com&code_challenge={}&code_challenge_method=S512", challenge))
+            .dispatch();
+        assert_eq!(response.status(), Status::TemporaryRedirect);
+        let response_url = Url::parse(response.headers().get("Location").next().unwrap()).unwrap();
+        let params: HashMap<String, String> = response_url.query_pairs().into_owned().collect();
+        assert_eq!(params.get("error"), Some(&"invalid_request".to_string()));
+    }
+
     #[test]
     fn test_state() {
         let client_id = "foo";
@@ -281,11 +759,12 @@ mod tests {
         let signature = "0x87b709d1e84aab056cf089af31e8d7c891d6f363663ff3eeb4bbb4c4e0602b2e3edf117fe548626b8d83e3b2c530cb55e2baff29ca54dbd495bb45764d9aa44c1c".to_string();
         let state = "state".to_string();
         let client = Client::tracked(rocket()).expect("valid rocket instance");
+        issue_challenge(&client, &nonce);
 
         let response = client
             .get(format!(
-                "/authorize?client_id={}&realm=okt&redirect_uri=https://example.com&nonce={}&contract={}&account={}&signature={}&state={}",
-                client_id, nonce, contract, account, signature, state
+                "/authorize?client_id={}&realm=okt&redirect_uri=https://example.com&nonce={}&contract={}&account={}&signature={}&state={}&challenge={}",
+                client_id, nonce, contract, account, signature, state, nonce
             ))
             .dispatch();
         assert_eq!(response.status(), Status::TemporaryRedirect);
@@ -314,11 +793,12 @@ mod tests {
         let code = "code".to_string();
         let id_token = "id_token".to_string();
         let client = Client::tracked(rocket()).expect("valid rocket instance");
+        issue_challenge(&client, &nonce);
 
         let response = client
             .get(format!(
-                "/authorize?client_id={}&realm=okt&redirect_uri=https://example.com&nonce={}&contract={}&account={}&signature={}&state={}&code={}&id_token={}&response_type=code+id_token",
-                client_id, nonce, contract, account, signature, state, code, id_token
+                "/authorize?client_id={}&realm=okt&redirect_uri=https://example.com&nonce={}&contract={}&account={}&signature={}&state={}&code={}&id_token={}&response_type=code+id_token&challenge={}",
+                client_id, nonce, contract, account, signature, state, code, id_token, nonce
             ))
             .dispatch();
         assert_eq!(response.status(), Status::TemporaryRedirect);
@@ -345,7 +825,7 @@ mod tests {
         let client = Client::tracked(rocket()).expect("valid rocket instance");
         let response = client
             .get(format!(
-                "/authorize?client_id={}&realm=kovan&redirect_uri=unused&contract={}",
+                "/authorize?client_id={}&realm=kovan&redirect_uri=https://example.com&contract={}",
                 client_id, contract
             ))
             .dispatch();
@@ -410,16 +890,218 @@ mod tests {
         let signature = "0x87b709d1e84aab056cf089af31e8d7c891d6f363663ff3eeb4bbb4c4e0602b2e3edf117fe548626b8d83e3b2c530cb55e2baff29ca54dbd495bb45764d9aa44c1c".to_string();
 
         let client = Client::tracked(rocket()).expect("valid rocket instance");
+        issue_challenge(&client, &nonce);
+
+        let response = client
+            .get(format!(
+                "/authorize?client_id={}&realm=okt&redirect_uri=https://example.com&nonce={}&contract={}&account={}&signature={}&challenge={}",
+                client_id, nonce, contract, account, signature, nonce
+            ))
+            .dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+    }
+
+    #[test]
+    fn login_with_full_nonces() {
+        let client = Client::tracked(rocket()).expect("valid rocket instance");
+        let store = client.rocket().state::<Store>().unwrap();
+        let expires_at = Utc::now() + Duration::minutes(5);
+        for nonce in 0..10_000 {
+            block_on(store.insert_nonce(&nonce.to_string(), expires_at)).unwrap();
+        }
+
+        let response = client
+            .get("/authorize?client_id=foo&realm=okt&redirect_uri=https://example.com")
+            .dispatch();
+        assert_eq!(response.status(), Status::TemporaryRedirect);
+        let response_url = Url::parse(response.headers().get("Location").next().unwrap()).unwrap();
+        let challenge = response_url
+            .query_pairs()
+            .find(|(name, _)| name == "challenge")
+            .map(|(_, challenge)| challenge.to_string())
+            .unwrap();
+
+        let key = wallet::key();
+        let account = wallet::address(&key);
+        let contract = "0x886B6781CD7dF75d8440Aba84216b2671AEFf9A4";
+        let expiry = (Utc::now() + Duration::minutes(5)).timestamp() as u64;
+        let typed_data = LoginTypedData::new(
+            "localhost:8000",
+            65,
+            &account,
+            &challenge,
+            "foo",
+            contract,
+            expiry,
+        )
+        .unwrap();
+        let signature = wallet::sign_hash(&key, &typed_data.message());
+        let login = format!(
+            "/authorize?client_id=foo&realm=okt&redirect_uri=https://example.com&nonce=n&contract={}&account={}&signature={}&expiry={}&challenge={}",
+            contract, account, signature, expiry, challenge
+        );
+        // the challenge is accepted, only the ownership can not be checked without a node
+        let response = client.get(&login).dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+        let response = client.get(&login).dispatch();
+        assert_eq!(
+            response.into_string().unwrap(),
+            "challenge unknown or already used"
+        );
+    }
+
+    fn siwe_message(domain: &str, account: &str, chain_id: i32, nonce: &str) -> String {
+        let issued_at = Utc::now();
+        format!(
+            "{} wants you to sign in with your Ethereum account:\n{}\n\nURI: http://{}\nVersion: 1\nChain ID: {}\nNonce: {}\nIssued At: {}\nExpiration Time: {}",
+            domain,
+            account,
+            domain,
+            chain_id,
+            nonce,
+            issued_at.to_rfc3339(),
+            (issued_at + Duration::minutes(5)).to_rfc3339()
+        )
+    }
+
+    #[test]
+    fn siwe_message_wrong_domain() {
+        let key = wallet::key();
+        let account = wallet::address(&key);
+        let contract = "0x886B6781CD7dF75d8440Aba84216b2671AEFf9A4";
+        let nonce = "dotzxrenodo";
+        let message = siwe_message("evil.example.com", &account, 65, nonce);
+        let signature = wallet::sign(&key, &message);
+
+        let client = Client::tracked(rocket()).expect("valid rocket instance");
+        issue_challenge(&client, nonce);
+
+        let response = client
+            .get(format!(
+                "/authorize?client_id=foo&realm=okt&redirect_uri=https://example.com&nonce={}&contract={}&account={}&signature={}&message={}&challenge={}",
+                nonce, contract, account, signature, urlencoding(&message), nonce
+            ))
+            .dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+        assert_eq!(response.into_string().unwrap(), "domain mismatch");
+    }
+
+    #[test]
+    fn siwe_message_valid_signature() {
+        let key = wallet::key();
+        let account = wallet::address(&key);
+        let contract = "0x886B6781CD7dF75d8440Aba84216b2671AEFf9A4";
+        let nonce = "dotzxrenodo";
+        let message = siwe_message("localhost:8000", &account, 65, nonce);
+        let signature = wallet::sign(&key, &message);
+
+        let client = Client::tracked(rocket()).expect("valid rocket instance");
+        issue_challenge(&client, nonce);
+
+        let response = client
+            .get(format!(
+                "/authorize?client_id=foo&realm=okt&redirect_uri=https://example.com&nonce={}&contract={}&account={}&signature={}&message={}&challenge={}",
+                nonce, contract, account, signature, urlencoding(&message), nonce
+            ))
+            .dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+        assert_eq!(response.into_string().unwrap(), "account is no owner");
+    }
+
+    #[test]
+    fn typed_data_valid_signature() {
+        let key = wallet::key();
+        let account = wallet::address(&key);
+        let contract = "0x886B6781CD7dF75d8440Aba84216b2671AEFf9A4";
+        let nonce = "dotzxrenodo";
+        let expiry = (Utc::now() + Duration::minutes(5)).timestamp() as u64;
+        let typed_data = LoginTypedData::new(
+            "localhost:8000",
+            65,
+            &account,
+            nonce,
+            "foo",
+            contract,
+            expiry,
+        )
+        .unwrap();
+        let signature = wallet::sign_hash(&key, &typed_data.message());
+
+        let client = Client::tracked(rocket()).expect("valid rocket instance");
+        issue_challenge(&client, nonce);
+
+        let response = client
+            .get(format!(
+                "/authorize?client_id=bar&realm=okt&redirect_uri=https://example.com&nonce={}&contract={}&account={}&signature={}&expiry={}&challenge={}",
+                nonce, contract, account, signature, expiry, nonce
+            ))
+            .dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+        assert_eq!(response.into_string().unwrap(), "no valide signature");
 
         let response = client
             .get(format!(
-                "/authorize?client_id={}&realm=okt&redirect_uri=https://example.com&nonce={}&contract={}&account={}&signature={}",
-                client_id, nonce, contract, account, signature
+                "/authorize?client_id=foo&realm=okt&redirect_uri=https://example.com&nonce={}&contract={}&account={}&signature={}&expiry={}&challenge={}",
+                nonce, contract, account, signature, expiry, nonce
             ))
             .dispatch();
         assert_eq!(response.status(), Status::Unauthorized);
     }
 
+    #[test]
+    fn erc1155_without_token_ids() {
+        let client_id = "foo";
+        let contract = "0x886B6781CD7dF75d8440Aba84216b2671AEFf9A4";
+        let account = "0x9c9e8eabd947658bdb713e0d3ebfe56860abdb8d".to_string();
+        let nonce = "dotzxrenodo".to_string();
+        let signature = "0x87b709d1e84aab056cf089af31e8d7c891d6f363663ff3eeb4bbb4c4e0602b2e3edf117fe548626b8d83e3b2c530cb55e2baff29ca54dbd495bb45764d9aa44c1c".to_string();
+
+        let client = Client::tracked(rocket()).expect("valid rocket instance");
+        issue_challenge(&client, &nonce);
+
+        let response = client
+            .get(format!(
+                "/authorize?client_id={}&realm=okt&redirect_uri=https://example.com&nonce={}&contract={}&account={}&signature={}&challenge={}&token_standard=erc1155",
+                client_id, nonce, contract, account, signature, nonce
+            ))
+            .dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+        assert_eq!(response.into_string().unwrap(), "token_ids missing");
+
+        let response = client
+            .get(format!(
+                "/authorize?client_id={}&realm=okt&redirect_uri=https://example.com&nonce={}&contract={}&account={}&signature={}&challenge={}&token_standard=erc42",
+                client_id, nonce, contract, account, signature, nonce
+            ))
+            .dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+        assert_eq!(
+            response.into_string().unwrap(),
+            "unsupported token standard"
+        );
+    }
+
+    #[test]
+    fn invalid_token_id() {
+        let client_id = "foo";
+        let contract = "0x886B6781CD7dF75d8440Aba84216b2671AEFf9A4";
+        let account = "0x9c9e8eabd947658bdb713e0d3ebfe56860abdb8d".to_string();
+        let nonce = "dotzxrenodo".to_string();
+        let signature = "0x87b709d1e84aab056cf089af31e8d7c891d6f363663ff3eeb4bbb4c4e0602b2e3edf117fe548626b8d83e3b2c530cb55e2baff29ca54dbd495bb45764d9aa44c1c".to_string();
+
+        let client = Client::tracked(rocket()).expect("valid rocket instance");
+        issue_challenge(&client, &nonce);
+
+        let response = client
+            .get(format!(
+                "/authorize?client_id={}&realm=okt&redirect_uri=https://example.com&nonce={}&contract={}&account={}&signature={}&challenge={}&token_id=1,2",
+                client_id, nonce, contract, account, signature, nonce
+            ))
+            .dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+        assert_eq!(response.into_string().unwrap(), "invalid token id");
+    }
+
     #[test]
     fn account_not_owner() {
         let client_id = "foo";
@@ -429,11 +1111,12 @@ mod tests {
         let signature = "0x620335720244ea6317d39a8f70d0df98d5e8299ad64d0b423f136002fa4636dc2bc1c75c7b6c9a09669e01d48bf91ad78ebafb82d2065573be90f2ec2480874f1c".to_string();
 
         let client = Client::tracked(rocket()).expect("valid rocket instance");
+        issue_challenge(&client, &nonce);
 
         let response = client
             .get(format!(
-                "/authorize?client_id={}&realm=okt&redirect_uri=https://example.com&nonce={}&contract={}&account={}&signature={}",
-                client_id, nonce, contract, account, signature
+                "/authorize?client_id={}&realm=okt&redirect_uri=https://example.com&nonce={}&contract={}&account={}&signature={}&challenge={}",
+                client_id, nonce, contract, account, signature, nonce
             ))
             .dispatch();
         assert_eq!(response.status(), Status::Unauthorized);
@@ -449,11 +1132,12 @@ mod tests {
         let signature = "0x87b709d1e84aab056cf089af31e8d7c891d6f363663ff3eeb4bbb4c4e0602b2e3edf117fe548626b8d83e3b2c530cb55e2baff29ca54dbd495bb45764d9aa44c1c".to_string();
 
         let client = Client::tracked(rocket()).expect("valid rocket instance");
+        issue_challenge(&client, &nonce);
 
         let response = client
             .get(format!(
-                "/authorize?client_id={}&realm=okt&redirect_uri=https://example.com&nonce={}&contract={}&account={}&signature={}",
-                client_id, nonce, contract, account, signature
+                "/authorize?client_id={}&realm=okt&redirect_uri=https://example.com&nonce={}&contract={}&account={}&signature={}&challenge={}",
+                client_id, nonce, contract, account, signature, nonce
             ))
             .dispatch();
         assert_eq!(response.status(), Status::TemporaryRedirect);
@@ -3,26 +3,27 @@ use openidconnect::{
     AdditionalClaims, EndUserEmail, EndUserName, StandardClaims, SubjectIdentifier,
 };
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
 
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
 pub struct Claims {
     pub account: String,
+    // taken by the nonce of the id token claims when read back from a stored token
+    #[serde(default)]
     pub nonce: String,
     pub signature: String,
     pub chain_id: i32,
     pub node: String,
     pub contract: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token_id: Option<String>,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub balance: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub satisfied: Vec<String>,
 }
 
 impl AdditionalClaims for Claims {}
 
-pub struct ClaimsMutex {
-    pub standard_claims: Arc<Mutex<HashMap<String, StandardClaims<CoreGenderClaim>>>>,
-    pub additional_claims: Arc<Mutex<HashMap<String, Claims>>>,
-}
-
 pub fn standard_claims(account: &String) -> StandardClaims<CoreGenderClaim> {
     StandardClaims::new(SubjectIdentifier::new(account.clone()))
         .set_email(Some(EndUserEmail::new("no-reply@example.com".to_string())))
@@ -30,6 +31,7 @@ pub fn standard_claims(account: &String) -> StandardClaims<CoreGenderClaim> {
         .set_name(Some(EndUserName::new("anonymous".to_string()).into()))
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn additional_claims(
     account: &String,
     nonce: &String,
@@ -37,6 +39,9 @@ pub fn additional_claims(
     chain_id: &i32,
     node: &String,
     contract: &String,
+    token_id: &Option<String>,
+    balance: &String,
+    satisfied: &[String],
 ) -> Claims {
     Claims {
         account: account.clone(),
@@ -45,5 +50,8 @@ pub fn additional_claims(
         chain_id: *chain_id,
         node: node.clone(),
         contract: contract.clone(),
+        token_id: token_id.clone(),
+        balance: balance.clone(),
+        satisfied: satisfied.to_vec(),
     }
 }
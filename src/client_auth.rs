@@ -0,0 +1,417 @@
+use chrono::{DateTime, TimeZone, Utc};
+use openidconnect::core::{CoreJsonWebKey, CoreJsonWebKeyUse, CoreJwsSigningAlgorithm};
+use openidconnect::JsonWebKey;
+use rocket::request::{FromRequest, Outcome, Request};
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::config::{get_client, ClientConfig, Config};
+use crate::storage::Store;
+
+pub const JWT_BEARER_ASSERTION_TYPE: &str =
+    "urn:ietf:params:oauth:client-assertion-type:jwt-bearer";
+
+/// Client assertions may expire at most five minutes ahead, so their `jti` is kept only briefly.
+const MAX_ASSERTION_LIFETIME_SECONDS: i64 = 300;
+
+/// Client id and secret of an `Authorization: Basic` header, if the request has one.
+#[derive(Debug)]
+pub struct BasicAuth(pub Option<(String, String)>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for BasicAuth {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let credentials = req
+            .headers()
+            .get_one("Authorization")
+            .and_then(|header| header.strip_prefix("Basic "))
+            .and_then(|encoded| base64::decode(encoded.trim()).ok())
+            .and_then(|decoded| String::from_utf8(decoded).ok())
+            .and_then(|decoded| {
+                decoded.split_once(':').map(|(client_id, client_secret)| {
+                    (form_decode(client_id), form_decode(client_secret))
+                })
+            });
+        Outcome::Success(BasicAuth(credentials))
+    }
+}
+
+/// Client credentials as sent to the token endpoint.
+#[derive(Debug, Default)]
+pub struct ClientCredentials {
+    pub basic: Option<(String, String)>,
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+    pub client_assertion_type: Option<String>,
+    pub client_assertion: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct JwtHeader {
+    alg: CoreJwsSigningAlgorithm,
+    kid: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AssertionClaims {
+    iss: String,
+    sub: String,
+    aud: Value,
+    exp: i64,
+    jti: Option<String>,
+}
+
+// RFC 6749 section 2.3.1 requires form encoding of the basic credentials.
+fn form_decode(value: &str) -> String {
+    url::form_urlencoded::parse(format!("v={}", value).as_bytes())
+        .next()
+        .map(|(_, value)| value.into_owned())
+        .unwrap_or_default()
+}
+
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+fn decode_segment(segment: &str) -> Result<Vec<u8>, String> {
+    base64::decode_config(segment, base64::URL_SAFE_NO_PAD)
+        .map_err(|_| "malformed client assertion".to_string())
+}
+
+/// The keys of the client to verify its client assertions, all in `jwks` but those with `use` `enc`.
+pub fn signing_keys(client: &ClientConfig) -> Vec<CoreJsonWebKey> {
+    client
+        .jwks
+        .iter()
+        .flatten()
+        .filter(|key| key.key_use() != Some(&CoreJsonWebKeyUse::Encryption))
+        .cloned()
+        .collect()
+}
+
+/// Returns the token endpoint auth method of the client, derived from its credentials when not configured.
+pub fn auth_method(client: &ClientConfig) -> String {
+    match (
+        &client.token_endpoint_auth_method,
+        &client.client_secret,
+        signing_keys(client).is_empty(),
+    ) {
+        (Some(method), _, _) => method.clone(),
+        (None, Some(_), _) => "client_secret_basic".to_string(),
+        (None, None, false) => "private_key_jwt".to_string(),
+        (None, None, true) => "none".to_string(),
+    }
+}
+
+/// Verifies a `private_key_jwt` client assertion as in RFC 7523 against the keys of the client,
+/// returning its `jti` and expiry.
+pub fn verify_client_assertion(
+    client_id: &str,
+    keys: &[CoreJsonWebKey],
+    assertion: &str,
+    audiences: &[String],
+) -> Result<(String, DateTime<Utc>), String> {
+    let segments: Vec<&str> = assertion.split('.').collect();
+    if segments.len() != 3 {
+        return Err("malformed client assertion".to_string());
+    }
+    let header: JwtHeader = serde_json::from_slice(&decode_segment(segments[0])?)
+        .map_err(|_| "malformed client assertion".to_string())?;
+    if header.alg == CoreJwsSigningAlgorithm::None {
+        return Err("unsigned client assertion".to_string());
+    }
+    let signature = decode_segment(segments[2])?;
+    let message = format!("{}.{}", segments[0], segments[1]);
+    let verified = keys
+        .iter()
+        .filter(|key| match (&header.kid, key.key_id()) {
+            (Some(kid), Some(key_id)) => kid == key_id.as_str(),
+            _ => true,
+        })
+        .any(|key| {
+            key.verify_signature(&header.alg, message.as_bytes(), &signature)
+                .is_ok()
+        });
+    if !verified {
+        return Err("invalid client assertion signature".to_string());
+    }
+
+    let claims: AssertionClaims = serde_json::from_slice(&decode_segment(segments[1])?)
+        .map_err(|_| "malformed client assertion".to_string())?;
+    if claims.iss != client_id || claims.sub != client_id {
+        return Err("client assertion issued for other client".to_string());
+    }
+    let audience_matches = |aud: &Value| {
+        aud.as_str()
+            .map(|aud| audiences.iter().any(|audience| audience == aud))
+            .unwrap_or(false)
+    };
+    let audience = match &claims.aud {
+        Value::Array(aud) => aud.iter().any(audience_matches),
+        aud => audience_matches(aud),
+    };
+    if !audience {
+        return Err("client assertion for other audience".to_string());
+    }
+    let now = Utc::now().timestamp();
+    if claims.exp <= now {
+        return Err("client assertion expired".to_string());
+    }
+    if claims.exp > now + MAX_ASSERTION_LIFETIME_SECONDS {
+        return Err("client assertion expires too late".to_string());
+    }
+    let jti = claims
+        .jti
+        .ok_or_else(|| "client assertion without jti".to_string())?;
+    Ok((jti, Utc.timestamp(claims.exp, 0)))
+}
+
+/// Authenticates the client with the method it is registered for and returns its client id.
+///
+/// Clients limited to some `realms` can only authenticate in those, and every client assertion
+/// is accepted only once.
+pub async fn authenticate_client(
+    config: &Config,
+    store: &Store,
+    realm: &str,
+    credentials: &ClientCredentials,
+) -> Result<String, String> {
+    let client_id = match (&credentials.basic, &credentials.client_id) {
+        (Some((basic_id, _)), Some(client_id)) if basic_id != client_id => {
+            return Err("client id mismatch".to_string())
+        }
+        (Some((client_id, _)), _) => client_id.clone(),
+        (None, Some(client_id)) => client_id.clone(),
+        (None, None) => return Err("client id missing".to_string()),
+    };
+    let client = get_client(config, &client_id).ok_or("unknown client")?;
+    if !client.allows_realm(realm) {
+        return Err("client not registered for this realm".to_string());
+    }
+
+    let secret = |client_secret: &Option<String>| match (&client.client_secret, client_secret) {
+        (Some(secret), Some(client_secret))
+            if constant_time_eq(secret.as_bytes(), client_secret.as_bytes()) =>
+        {
+            Ok(client_id.clone())
+        }
+        _ => Err("invalid client secret".to_string()),
+    };
+
+    match auth_method(client).as_str() {
+        "client_secret_basic" => secret(&credentials.basic.clone().map(|(_, secret)| secret)),
+        "client_secret_post" if credentials.basic.is_none() => secret(&credentials.client_secret),
+        "private_key_jwt" => {
+            if credentials.client_assertion_type.as_deref() != Some(JWT_BEARER_ASSERTION_TYPE) {
+                return Err("client assertion missing".to_string());
+            }
+            let audiences = [
+                format!("{}/{}/token", config.ext_hostname, realm),
+                format!("{}/{}", config.ext_hostname, realm),
+                format!("{}/token", config.ext_hostname),
+            ];
+            let (jti, expires_at) = verify_client_assertion(
+                &client_id,
+                &signing_keys(client),
+                credentials.client_assertion.as_deref().unwrap_or_default(),
+                &audiences,
+            )?;
+            if !store
+                .insert_jti(&format!("{}:{}", client_id, jti), expires_at)
+                .await?
+            {
+                return Err("client assertion replayed".to_string());
+            }
+            Ok(client_id)
+        }
+        "none" if credentials.basic.is_none() && credentials.client_secret.is_none() => {
+            Ok(client_id)
+        }
+        _ => Err("unsupported client authentication".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+    use openidconnect::core::CoreRsaPrivateSigningKey;
+    use openidconnect::{JsonWebKeyId, PrivateSigningKey};
+    use serde_json::json;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    fn encode(value: &Value) -> String {
+        base64::encode_config(value.to_string(), base64::URL_SAFE_NO_PAD)
+    }
+
+    fn assertion(key: &CoreRsaPrivateSigningKey, claims: Value) -> String {
+        let message = format!(
+            "{}.{}",
+            encode(&json!({"alg": "RS256", "kid": "client-key"})),
+            encode(&claims)
+        );
+        let signature = key
+            .sign(
+                &CoreJwsSigningAlgorithm::RsaSsaPkcs1V15Sha256,
+                message.as_bytes(),
+            )
+            .unwrap();
+        format!(
+            "{}.{}",
+            message,
+            base64::encode_config(signature, base64::URL_SAFE_NO_PAD)
+        )
+    }
+
+    fn key(kid: &str) -> CoreRsaPrivateSigningKey {
+        CoreRsaPrivateSigningKey::from_pem(
+            include_str!("../do-not-use.pem"),
+            Some(JsonWebKeyId::new(kid.to_string())),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_verify_client_assertion() {
+        let key = key("client-key");
+        let keys = vec![key.as_verification_key()];
+        let audiences = vec!["http://localhost:8000/okt/token".to_string()];
+        let exp = Utc::now().timestamp() + 60;
+
+        let valid = assertion(
+            &key,
+            json!({"iss": "foo", "sub": "foo", "aud": audiences[0], "exp": exp, "jti": "1"}),
+        );
+        assert_eq!(
+            verify_client_assertion("foo", &keys, &valid, &audiences),
+            Ok(("1".to_string(), Utc.timestamp(exp, 0)))
+        );
+        assert!(verify_client_assertion("bar", &keys, &valid, &audiences).is_err());
+        assert!(verify_client_assertion("foo", &[], &valid, &audiences).is_err());
+        // the kid of the assertion matches no key of the client
+        let other_keys = vec![self::key("other-key").as_verification_key()];
+        assert_eq!(
+            verify_client_assertion("foo", &other_keys, &valid, &audiences),
+            Err("invalid client assertion signature".to_string())
+        );
+
+        let without_jti = assertion(
+            &key,
+            json!({"iss": "foo", "sub": "foo", "aud": audiences[0], "exp": exp}),
+        );
+        assert_eq!(
+            verify_client_assertion("foo", &keys, &without_jti, &audiences),
+            Err("client assertion without jti".to_string())
+        );
+
+        let long_lived = assertion(
+            &key,
+            json!({"iss": "foo", "sub": "foo", "aud": audiences[0], "exp": exp + 3600, "jti": "2"}),
+        );
+        assert_eq!(
+            verify_client_assertion("foo", &keys, &long_lived, &audiences),
+            Err("client assertion expires too late".to_string())
+        );
+
+        let expired = assertion(
+            &key,
+            json!({"iss": "foo", "sub": "foo", "aud": [audiences[0]], "exp": exp - 120}),
+        );
+        assert_eq!(
+            verify_client_assertion("foo", &keys, &expired, &audiences),
+            Err("client assertion expired".to_string())
+        );
+
+        let other_audience = assertion(
+            &key,
+            json!({"iss": "foo", "sub": "foo", "aud": "https://example.com", "exp": exp}),
+        );
+        assert!(verify_client_assertion("foo", &keys, &other_audience, &audiences).is_err());
+
+        let tampered = valid.replacen('.', ".e30", 1);
+        assert!(verify_client_assertion("foo", &keys, &tampered, &audiences).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_authenticate_client() {
+        let key = key("client-key");
+        let config = Config {
+            ext_hostname: "http://localhost:8000".into(),
+            clients: HashMap::from([(
+                "signed".into(),
+                ClientConfig {
+                    realms: Some(vec!["okt".into()]),
+                    jwks: Some(vec![key.as_verification_key()]),
+                    ..Default::default()
+                },
+            )]),
+            ..Default::default()
+        };
+        let store: Store = Arc::new(MemoryStorage::default());
+        let credentials = |jti: &str| ClientCredentials {
+            client_id: Some("signed".into()),
+            client_assertion_type: Some(JWT_BEARER_ASSERTION_TYPE.into()),
+            client_assertion: Some(assertion(
+                &key,
+                json!({
+                    "iss": "signed",
+                    "sub": "signed",
+                    "aud": "http://localhost:8000/okt/token",
+                    "exp": Utc::now().timestamp() + 60,
+                    "jti": jti,
+                }),
+            )),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            authenticate_client(&config, &store, "okt", &credentials("1")).await,
+            Ok("signed".to_string())
+        );
+        assert_eq!(
+            authenticate_client(&config, &store, "okt", &credentials("1")).await,
+            Err("client assertion replayed".to_string())
+        );
+        assert!(
+            authenticate_client(&config, &store, "okt", &credentials("2"))
+                .await
+                .is_ok()
+        );
+        assert_eq!(
+            authenticate_client(&config, &store, "kovan", &credentials("3")).await,
+            Err("client not registered for this realm".to_string())
+        );
+    }
+
+    #[test]
+    fn test_auth_method() {
+        let key = |key_use: &str| {
+            serde_json::from_value::<CoreJsonWebKey>(json!({
+                "kty": "RSA", "use": key_use, "e": "AQAB", "n": "rkQ12gGZaB5pYIuGVl9QpX2cfx5c"
+            }))
+            .unwrap()
+        };
+        let mut client = ClientConfig {
+            jwks: Some(vec![key("enc")]),
+            ..Default::default()
+        };
+        assert_eq!(auth_method(&client), "none");
+        assert!(signing_keys(&client).is_empty());
+
+        client.jwks = Some(vec![key("enc"), key("sig")]);
+        assert_eq!(auth_method(&client), "private_key_jwt");
+        assert_eq!(signing_keys(&client), vec![key("sig")]);
+
+        client.client_secret = Some("secret".into());
+        assert_eq!(auth_method(&client), "client_secret_basic");
+    }
+
+    #[test]
+    fn test_form_decode() {
+        assert_eq!(form_decode("my%3Aclient"), "my:client");
+        assert_eq!(form_decode("a+b"), "a b");
+    }
+}
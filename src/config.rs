@@ -1,24 +1,176 @@
+use chrono::{DateTime, Utc};
 use rocket::response::content;
 use rocket::State;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use url::Url;
 
 use openidconnect::core::{
-    CoreClaimName, CoreJwsSigningAlgorithm, CoreProviderMetadata, CoreResponseType,
-    CoreSubjectIdentifierType,
+    CoreAuthDisplay, CoreClaimName, CoreClaimType, CoreClientAuthMethod, CoreGrantType,
+    CoreJsonWebKey, CoreJsonWebKeyUse, CoreJweKeyManagementAlgorithm, CoreResponseMode,
+    CoreResponseType, CoreSubjectIdentifierType,
 };
 use openidconnect::{
-    AuthUrl, EmptyAdditionalProviderMetadata, IssuerUrl, JsonWebKeySetUrl, ResponseTypes, Scope,
-    TokenUrl, UserInfoUrl,
+    AdditionalProviderMetadata, AuthUrl, IssuerUrl, JsonWebKeySetUrl, ProviderMetadata,
+    ResponseTypes, Scope, TokenUrl, UserInfoUrl,
+};
+
+use crate::jose::{
+    NftJsonWebKey, NftJsonWebKeyType, NftJweContentEncryptionAlgorithm, NftJwsSigningAlgorithm,
 };
+use crate::jwe::{CONTENT_ENCRYPTION_ALGORITHMS, KEY_MANAGEMENT_ALGORITHMS};
+use crate::pkce::CODE_CHALLENGE_METHODS;
+use crate::policy::Policy;
+use crate::web3::TokenStandard;
+
+const SUPPORTED_RESPONSE_TYPES: [&str; 3] = ["code", "id_token", "token"];
+
+/// Scopes granted to every token, as all claims are released.
+pub const SCOPES: [&str; 2] = ["openid", "email"];
+
+/// A registered client, identified by its `client_id`.
+///
+/// `response_types` and `realms` allow everything when not configured.
+#[derive(Debug, Default, PartialEq, Clone, Deserialize)]
+pub struct ClientConfig {
+    #[serde(default)]
+    pub redirect_uris: Vec<String>,
+    pub response_types: Option<Vec<String>>,
+    pub realms: Option<Vec<String>>,
+    pub contract: Option<String>,
+    pub client_secret: Option<String>,
+    pub jwks: Option<Vec<CoreJsonWebKey>>,
+    pub token_endpoint_auth_method: Option<String>,
+    #[serde(default)]
+    pub require_pkce: bool,
+    #[serde(default)]
+    pub jwt_access_token: bool,
+    pub access_token_audience: Option<String>,
+    pub token_id: Option<String>,
+    pub token_standard: Option<TokenStandard>,
+    pub min_balance: Option<String>,
+    pub policy: Option<Policy>,
+    pub id_token_encrypted_response_alg: Option<CoreJweKeyManagementAlgorithm>,
+    pub id_token_encrypted_response_enc: Option<NftJweContentEncryptionAlgorithm>,
+    pub userinfo_encrypted_response_alg: Option<CoreJweKeyManagementAlgorithm>,
+    pub userinfo_encrypted_response_enc: Option<NftJweContentEncryptionAlgorithm>,
+}
+
+impl ClientConfig {
+    /// The encryption of id tokens, if registered, with A128GCM unless configured otherwise.
+    pub fn id_token_encryption(
+        &self,
+    ) -> Option<(
+        &CoreJweKeyManagementAlgorithm,
+        NftJweContentEncryptionAlgorithm,
+    )> {
+        let alg = self.id_token_encrypted_response_alg.as_ref()?;
+        let enc = self.id_token_encrypted_response_enc.clone();
+        Some((
+            alg,
+            enc.unwrap_or(NftJweContentEncryptionAlgorithm::Aes128Gcm),
+        ))
+    }
+
+    /// The encryption of userinfo responses, if registered, with A128GCM unless configured
+    /// otherwise.
+    pub fn userinfo_encryption(
+        &self,
+    ) -> Option<(
+        &CoreJweKeyManagementAlgorithm,
+        NftJweContentEncryptionAlgorithm,
+    )> {
+        let alg = self.userinfo_encrypted_response_alg.as_ref()?;
+        let enc = self.userinfo_encrypted_response_enc.clone();
+        Some((
+            alg,
+            enc.unwrap_or(NftJweContentEncryptionAlgorithm::Aes128Gcm),
+        ))
+    }
 
-#[derive(Debug, PartialEq, Deserialize)]
+    /// Compares the redirect uri with the registered ones after normalizing both.
+    pub fn allows_redirect_uri(&self, redirect_uri: &Url) -> bool {
+        self.redirect_uris
+            .iter()
+            .filter_map(|registered| Url::parse(registered).ok())
+            .any(|registered| &registered == redirect_uri)
+    }
+
+    pub fn allows_response_type(&self, response_type: &str) -> bool {
+        match &self.response_types {
+            Some(response_types) => response_types.iter().any(|allowed| {
+                normalize_response_type(allowed) == normalize_response_type(response_type)
+            }),
+            None => true,
+        }
+    }
+
+    pub fn allows_realm(&self, realm: &str) -> bool {
+        match &self.realms {
+            Some(realms) => realms.iter().any(|allowed| allowed == realm),
+            None => true,
+        }
+    }
+}
+
+/// Sorts the space separated values, so `id_token code` equals `code id_token`.
+pub fn normalize_response_type(response_type: &str) -> String {
+    let mut values: Vec<&str> = response_type.split_whitespace().collect();
+    values.sort_unstable();
+    values.dedup();
+    values.join(" ")
+}
+
+pub fn is_supported_response_type(response_type: &str) -> bool {
+    let mut values = response_type.split_whitespace().peekable();
+    values.peek().is_some() && values.all(|value| SUPPORTED_RESPONSE_TYPES.contains(&value))
+}
+
+/// A key signing id tokens and JWT access tokens, published with its `kid`.
+///
+/// The key signs with the algorithm of its type, RSA, P-256 or Ed25519, and takes over from the
+/// previous key of that type at `active_from`, or is active from the start.
+#[derive(Debug, Default, PartialEq, Clone, Deserialize)]
+pub struct SigningKeyConfig {
+    pub kid: String,
+    #[serde(alias = "rsa_pem_file")]
+    pub pem_file: Option<String>,
+    #[serde(alias = "rsa_pem")]
+    pub pem: Option<String>,
+    pub active_from: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Default, PartialEq, Deserialize)]
 pub struct Config {
     pub ext_hostname: String,
-    pub key_id: String,
     pub node_provider: HashMap<String, String>,
     pub chain_id: HashMap<String, i32>,
-    pub rsa_pem: Option<String>,
+    #[serde(default)]
+    pub signing_keys: Vec<SigningKeyConfig>,
+    #[serde(default)]
+    pub realm_signing_keys: HashMap<String, Vec<SigningKeyConfig>>,
+    pub key_retention: Option<i64>,
+    pub admin_token: Option<String>,
+    #[serde(default)]
+    pub signing_alg: HashMap<String, NftJwsSigningAlgorithm>,
+    #[serde(default)]
+    pub legacy_signature: HashMap<String, bool>,
+    pub nonce_ttl: Option<i64>,
+    pub code_ttl: Option<i64>,
+    pub access_ttl: Option<i64>,
+    pub refresh_ttl: Option<i64>,
+    #[serde(default = "default_true")]
+    pub get_token_endpoint: bool,
+    pub storage_url: Option<String>,
+    pub max_entries: Option<usize>,
+    pub max_nonces: Option<usize>,
+    pub sweep_interval: Option<u64>,
+    #[serde(default)]
+    pub clients: HashMap<String, ClientConfig>,
+}
+
+fn default_true() -> bool {
+    true
 }
 
 pub fn get_chain_id(config: &Config, realm: &String) -> i32 {
@@ -51,6 +203,63 @@ pub fn get_node(config: &Config, realm: &String) -> String {
     }
 }
 
+pub fn get_client<'a>(config: &'a Config, client_id: &str) -> Option<&'a ClientConfig> {
+    config.clients.get(client_id)
+}
+
+pub fn get_domain(config: &Config) -> String {
+    let url = Url::parse(&config.ext_hostname).unwrap();
+    match url.port() {
+        Some(port) => format!("{}:{}", url.host_str().unwrap_or_default(), port),
+        None => url.host_str().unwrap_or_default().to_string(),
+    }
+}
+
+pub fn allows_legacy_signature(config: &Config, realm: &String) -> bool {
+    *config
+        .legacy_signature
+        .get(realm)
+        .or_else(|| config.legacy_signature.get("default"))
+        .unwrap_or(&false)
+}
+
+/// The algorithm signing the id tokens of the realm, RS256 unless configured.
+pub fn get_signing_alg(config: &Config, realm: &str) -> NftJwsSigningAlgorithm {
+    config
+        .signing_alg
+        .get(realm)
+        .or_else(|| config.signing_alg.get("default"))
+        .cloned()
+        .unwrap_or(NftJwsSigningAlgorithm::RsaSsaPkcs1V15Sha256)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NftProviderMetadataFields {
+    pub code_challenge_methods_supported: Vec<String>,
+    pub revocation_endpoint: String,
+    pub introspection_endpoint: String,
+}
+
+impl AdditionalProviderMetadata for NftProviderMetadataFields {}
+
+pub type NftProviderMetadata = ProviderMetadata<
+    NftProviderMetadataFields,
+    CoreAuthDisplay,
+    CoreClientAuthMethod,
+    CoreClaimName,
+    CoreClaimType,
+    CoreGrantType,
+    NftJweContentEncryptionAlgorithm,
+    CoreJweKeyManagementAlgorithm,
+    NftJwsSigningAlgorithm,
+    NftJsonWebKeyType,
+    CoreJsonWebKeyUse,
+    NftJsonWebKey,
+    CoreResponseMode,
+    CoreResponseType,
+    CoreSubjectIdentifierType,
+>;
+
 #[get("/.well-known/openid-configuration")]
 pub fn default_configuration(config: &State<Config>) -> content::Json<String> {
     configuration(config, "default".into())
@@ -71,7 +280,7 @@ pub fn well_known_oauth_authorization_server(
 
 #[get("/<realm>/.well-known/openid-configuration")]
 pub fn configuration(config: &State<Config>, realm: String) -> content::Json<String> {
-    let provider_metadata = CoreProviderMetadata::new(
+    let provider_metadata = NftProviderMetadata::new(
         IssuerUrl::new(format!("{}/{}", config.ext_hostname, realm)).unwrap(),
         AuthUrl::new(format!("{}/{}/authorize", config.ext_hostname, realm)).unwrap(),
         JsonWebKeySetUrl::new(format!("{}/{}/jwk", config.ext_hostname, realm)).unwrap(),
@@ -80,19 +289,43 @@ pub fn configuration(config: &State<Config>, realm: String) -> content::Json<Str
             ResponseTypes::new(vec![CoreResponseType::Token, CoreResponseType::IdToken]),
         ],
         vec![CoreSubjectIdentifierType::Pairwise],
-        vec![CoreJwsSigningAlgorithm::RsaSsaPssSha256],
-        EmptyAdditionalProviderMetadata {},
+        vec![get_signing_alg(config, &realm)],
+        NftProviderMetadataFields {
+            code_challenge_methods_supported: CODE_CHALLENGE_METHODS
+                .iter()
+                .map(|method| method.to_string())
+                .collect(),
+            revocation_endpoint: format!("{}/{}/revoke", config.ext_hostname, realm),
+            introspection_endpoint: format!("{}/{}/introspect", config.ext_hostname, realm),
+        },
     )
     .set_token_endpoint(Some(
         TokenUrl::new(format!("{}/{}/token", config.ext_hostname, realm)).unwrap(),
     ))
+    .set_grant_types_supported(Some(vec![
+        CoreGrantType::AuthorizationCode,
+        CoreGrantType::Implicit,
+        CoreGrantType::RefreshToken,
+    ]))
+    .set_token_endpoint_auth_methods_supported(Some(vec![
+        CoreClientAuthMethod::ClientSecretBasic,
+        CoreClientAuthMethod::ClientSecretPost,
+        CoreClientAuthMethod::PrivateKeyJwt,
+        CoreClientAuthMethod::None,
+    ]))
     .set_userinfo_endpoint(Some(
         UserInfoUrl::new(format!("{}/{}/userinfo", config.ext_hostname, realm)).unwrap(),
     ))
-    .set_scopes_supported(Some(vec![
-        Scope::new("openid".to_string()),
-        Scope::new("email".to_string()),
-    ]))
+    .set_scopes_supported(Some(
+        SCOPES
+            .iter()
+            .map(|scope| Scope::new(scope.to_string()))
+            .collect(),
+    ))
+    .set_id_token_encryption_alg_values_supported(Some(KEY_MANAGEMENT_ALGORITHMS.to_vec()))
+    .set_id_token_encryption_enc_values_supported(Some(CONTENT_ENCRYPTION_ALGORITHMS.to_vec()))
+    .set_userinfo_encryption_alg_values_supported(Some(KEY_MANAGEMENT_ALGORITHMS.to_vec()))
+    .set_userinfo_encryption_enc_values_supported(Some(CONTENT_ENCRYPTION_ALGORITHMS.to_vec()))
     .set_claims_supported(Some(vec![
         CoreClaimName::new("sub".to_string()),
         CoreClaimName::new("aud".to_string()),
@@ -121,10 +354,9 @@ mod tests {
     fn test_chain_id() {
         let config = Config {
             ext_hostname: "".to_string(),
-            key_id: "".to_string(),
             node_provider: HashMap::from([("example".into(), "https://example.com".into())]),
             chain_id: HashMap::from([("main".into(), 1)]),
-            rsa_pem: None,
+            ..Default::default()
         };
         assert_eq!(get_chain_id(&config, &"main".to_string()), 1);
         assert_eq!(get_chain_id(&config, &"unknown".to_string()), 42);
@@ -136,10 +368,9 @@ mod tests {
     fn test_node() {
         let config = Config {
             ext_hostname: "".to_string(),
-            key_id: "".to_string(),
             node_provider: HashMap::from([("default".into(), "https://example.com".into())]),
             chain_id: HashMap::from([("default".into(), 1)]),
-            rsa_pem: None,
+            ..Default::default()
         };
         assert_eq!(
             get_node(&config, &"example".to_string()),
@@ -152,6 +383,79 @@ mod tests {
         assert_eq!(get_node(&config, &"1".to_string()), "https://example.com");
     }
 
+    #[test]
+    fn test_domain() {
+        let config = Config {
+            ext_hostname: "http://localhost:8000".to_string(),
+            node_provider: HashMap::new(),
+            chain_id: HashMap::new(),
+            legacy_signature: HashMap::from([("default".into(), true), ("main".into(), false)]),
+            ..Default::default()
+        };
+        assert_eq!(get_domain(&config), "localhost:8000");
+        assert!(allows_legacy_signature(&config, &"kovan".to_string()));
+        assert!(!allows_legacy_signature(&config, &"main".to_string()));
+    }
+
+    #[test]
+    fn test_legacy_signature_off_in_release() {
+        let figment = rocket::Config::figment().select("release");
+        let legacy_signature = figment
+            .extract_inner::<HashMap<String, bool>>("legacy_signature")
+            .unwrap_or_default();
+        let config = Config {
+            legacy_signature,
+            ..Default::default()
+        };
+        assert!(!allows_legacy_signature(&config, &"okt".to_string()));
+    }
+
+    #[test]
+    fn test_client() {
+        let config = Config {
+            clients: HashMap::from([(
+                "foo".into(),
+                ClientConfig {
+                    token_id: Some("42".into()),
+                    ..Default::default()
+                },
+            )]),
+            ..Default::default()
+        };
+        assert_eq!(
+            get_client(&config, "foo").unwrap().token_id,
+            Some("42".to_string())
+        );
+        assert!(get_client(&config, "bar").is_none());
+    }
+
+    #[test]
+    fn test_client_registration() {
+        let client = ClientConfig {
+            redirect_uris: vec!["https://example.com".into()],
+            response_types: Some(vec!["code id_token".into()]),
+            realms: Some(vec!["okt".into()]),
+            ..Default::default()
+        };
+        assert!(client.allows_redirect_uri(&Url::parse("https://example.com/").unwrap()));
+        assert!(!client.allows_redirect_uri(&Url::parse("https://example.com/evil").unwrap()));
+        assert!(!client.allows_redirect_uri(&Url::parse("https://evil.example.com").unwrap()));
+        assert!(client.allows_response_type("id_token code"));
+        assert!(!client.allows_response_type("code"));
+        assert!(client.allows_realm("okt"));
+        assert!(!client.allows_realm("kovan"));
+
+        let client = ClientConfig::default();
+        assert!(!client.allows_redirect_uri(&Url::parse("https://example.com").unwrap()));
+        assert!(client.allows_response_type("code"));
+        assert!(client.allows_realm("kovan"));
+
+        assert!(is_supported_response_type("code id_token"));
+        assert!(is_supported_response_type("token id_token"));
+        assert!(!is_supported_response_type(""));
+        assert!(!is_supported_response_type("code device"));
+    }
+
     #[test]
     fn test_endpoints() {
         let client = Client::tracked(rocket()).expect("valid rocket instance");
@@ -180,5 +484,44 @@ mod tests {
             .as_str()
             .unwrap()
             .ends_with("kovan/userinfo"));
+        assert_eq!(
+            config.get("code_challenge_methods_supported").unwrap(),
+            &serde_json::json!(["S256", "plain"])
+        );
+        assert_eq!(
+            config.get("revocation_endpoint").unwrap(),
+            "http://localhost:8000/kovan/revoke"
+        );
+        assert_eq!(
+            config.get("introspection_endpoint").unwrap(),
+            "http://localhost:8000/kovan/introspect"
+        );
+        assert_eq!(
+            config.get("id_token_signing_alg_values_supported").unwrap(),
+            &serde_json::json!(["RS256"])
+        );
+        assert_eq!(
+            config
+                .get("id_token_encryption_alg_values_supported")
+                .unwrap(),
+            &serde_json::json!(["RSA-OAEP", "RSA-OAEP-256", "ECDH-ES"])
+        );
+        assert_eq!(
+            config
+                .get("userinfo_encryption_enc_values_supported")
+                .unwrap(),
+            &serde_json::json!(["A128GCM", "A256GCM"])
+        );
+
+        for (realm, alg) in [("clv", "ES256"), ("heco", "EdDSA")] {
+            let response = client
+                .get(format!("/{}/.well-known/openid-configuration", realm))
+                .dispatch();
+            let config = response.into_json::<Value>().unwrap();
+            assert_eq!(
+                config.get("id_token_signing_alg_values_supported").unwrap(),
+                &serde_json::json!([alg])
+            );
+        }
     }
 }
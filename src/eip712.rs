@@ -0,0 +1,174 @@
+use chrono::{Duration, TimeZone, Utc};
+use std::str::FromStr;
+use web3::ethabi::{encode, Token};
+use web3::signing::keccak256;
+use web3::types::{Address, U256};
+
+use crate::web3::{typed_data_message, validate_signature};
+
+const DOMAIN_TYPE: &str = "EIP712Domain(string name,string version,uint256 chainId)";
+const LOGIN_TYPE: &str =
+    "Login(address account,string nonce,string client_id,address contract,uint256 expiry)";
+
+/// A login challenge signed as EIP-712 typed data through `eth_signTypedData_v4`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct LoginTypedData {
+    pub domain: String,
+    pub chain_id: i32,
+    pub account: Address,
+    pub nonce: String,
+    pub client_id: String,
+    pub contract: Address,
+    pub expiry: u64,
+}
+
+impl LoginTypedData {
+    pub fn new(
+        domain: &str,
+        chain_id: i32,
+        account: &str,
+        nonce: &str,
+        client_id: &str,
+        contract: &str,
+        expiry: u64,
+    ) -> Result<Self, String> {
+        Ok(LoginTypedData {
+            domain: domain.to_string(),
+            chain_id,
+            account: Address::from_str(account).map_err(|_| "invalid account")?,
+            nonce: nonce.to_string(),
+            client_id: client_id.to_string(),
+            contract: Address::from_str(contract).map_err(|_| "invalid contract")?,
+            expiry,
+        })
+    }
+
+    pub fn domain_separator(&self) -> [u8; 32] {
+        keccak256(&encode(&[
+            Token::FixedBytes(keccak256(DOMAIN_TYPE.as_bytes()).to_vec()),
+            Token::FixedBytes(keccak256(self.domain.as_bytes()).to_vec()),
+            Token::FixedBytes(keccak256("1".as_bytes()).to_vec()),
+            Token::Uint(U256::from(self.chain_id)),
+        ]))
+    }
+
+    pub fn struct_hash(&self) -> [u8; 32] {
+        keccak256(&encode(&[
+            Token::FixedBytes(keccak256(LOGIN_TYPE.as_bytes()).to_vec()),
+            Token::Address(self.account),
+            Token::FixedBytes(keccak256(self.nonce.as_bytes()).to_vec()),
+            Token::FixedBytes(keccak256(self.client_id.as_bytes()).to_vec()),
+            Token::Address(self.contract),
+            Token::Uint(U256::from(self.expiry)),
+        ]))
+    }
+
+    pub fn message(&self) -> [u8; 32] {
+        typed_data_message(self.domain_separator(), self.struct_hash())
+    }
+}
+
+/// Checks the expiry of the typed login challenge and verifies that the account signed it.
+///
+/// The expiry may be at most `nonce_ttl` seconds ahead, as long as the challenge lives, so a
+/// signature is not valid forever.
+pub async fn validate_typed_data(
+    typed_data: &LoginTypedData,
+    signature: &str,
+    node_provider: &str,
+    nonce_ttl: i64,
+) -> Result<(), String> {
+    let expiry = i64::try_from(typed_data.expiry)
+        .ok()
+        .and_then(|expiry| Utc.timestamp_opt(expiry, 0).single())
+        .ok_or_else(|| "invalid expiry".to_string())?;
+    let now = Utc::now();
+    if expiry <= now {
+        return Err("message expired".to_string());
+    }
+    if expiry > now + Duration::seconds(nonce_ttl) {
+        return Err("expiry too far ahead".to_string());
+    }
+    if !validate_signature(
+        format!("{:02X?}", typed_data.account),
+        typed_data.message(),
+        signature.to_string(),
+        node_provider.to_string(),
+    )
+    .await
+    {
+        return Err("no valide signature".to_string());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::wallet;
+
+    fn typed_data(account: &str, expiry: u64) -> LoginTypedData {
+        LoginTypedData::new(
+            "localhost:8000",
+            65,
+            account,
+            "dotzxrenodo",
+            "foo",
+            "0x886B6781CD7dF75d8440Aba84216b2671AEFf9A4",
+            expiry,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_new() {
+        assert!(LoginTypedData::new("localhost:8000", 65, "foo", "", "", "", 0).is_err());
+        let typed_data = typed_data("0x9c9e8eabd947658bdb713e0d3ebfe56860abdb8d", 0);
+        assert_ne!(typed_data.message(), typed_data.struct_hash());
+    }
+
+    #[tokio::test]
+    async fn test_validate_typed_data() {
+        let key = wallet::key();
+        let account = wallet::address(&key);
+        let node_provider = "http://localhost:8545";
+        let expiry = (Utc::now().timestamp() + 300) as u64;
+
+        let typed_data = typed_data(&account, expiry);
+        let signature = wallet::sign_hash(&key, &typed_data.message());
+        assert!(
+            validate_typed_data(&typed_data, &signature, node_provider, 300)
+                .await
+                .is_ok()
+        );
+
+        let mut other_client = typed_data.clone();
+        other_client.client_id = "bar".to_string();
+        assert!(
+            validate_typed_data(&other_client, &signature, node_provider, 300)
+                .await
+                .is_err()
+        );
+
+        let expired = self::typed_data(&account, 0);
+        let signature = wallet::sign_hash(&key, &expired.message());
+        assert_eq!(
+            validate_typed_data(&expired, &signature, node_provider, 300).await,
+            Err("message expired".to_string())
+        );
+
+        let far_ahead = self::typed_data(&account, (Utc::now().timestamp() + 3600) as u64);
+        let signature = wallet::sign_hash(&key, &far_ahead.message());
+        assert_eq!(
+            validate_typed_data(&far_ahead, &signature, node_provider, 300).await,
+            Err("expiry too far ahead".to_string())
+        );
+
+        let out_of_range = self::typed_data(&account, u64::MAX);
+        let signature = wallet::sign_hash(&key, &out_of_range.message());
+        assert_eq!(
+            validate_typed_data(&out_of_range, &signature, node_provider, 300).await,
+            Err("invalid expiry".to_string())
+        );
+    }
+}
@@ -0,0 +1,259 @@
+use chrono::Utc;
+use openidconnect::core::CoreErrorResponseType;
+use rocket::form::Form;
+use rocket::serde::json::Json;
+use rocket::State;
+use serde::{Deserialize, Serialize};
+
+use crate::client_auth::{auth_method, authenticate_client, BasicAuth, ClientCredentials};
+use crate::config::{get_client, Config, SCOPES};
+use crate::policy::verify_access;
+use crate::storage::Store;
+use crate::token::{storage_error, token_error, TokenError};
+
+#[derive(FromForm)]
+pub struct IntrospectData {
+    pub token: Option<String>,
+    pub check_ownership: Option<bool>,
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+    pub client_assertion_type: Option<String>,
+    pub client_assertion: Option<String>,
+}
+
+/// An introspection response as in RFC 7662 section 2.2, extended by the NFT claims.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Introspection {
+    pub active: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exp: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sub: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub contract: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub chain_id: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_id: Option<String>,
+}
+
+/// Describes an access token, which is inactive once expired, revoked or unknown.
+///
+/// With `check_ownership` the ownership of the NFT is verified again, so a transferred NFT
+/// makes the token inactive before it expires.
+pub async fn introspect(
+    config: &Config,
+    store: &Store,
+    token: &str,
+    check_ownership: bool,
+) -> Result<Introspection, TokenError> {
+    let (grant, additional_claims) = match store.get_session(token).await.map_err(storage_error)? {
+        Some(session) if session.grant.expires_at > Utc::now() => {
+            (session.grant, session.additional_claims)
+        }
+        _ => return Ok(Introspection::default()),
+    };
+
+    if check_ownership {
+        let client = get_client(config, &grant.client_id)
+            .ok_or_else(|| token_error(CoreErrorResponseType::InvalidClient, "unknown client"))?;
+        let access = verify_access(
+            config,
+            client,
+            &grant.condition,
+            &grant.chain,
+            &additional_claims.account,
+            &additional_claims.node,
+        )
+        .await
+        .map_err(|_| {
+            token_error(
+                CoreErrorResponseType::Extension("temporarily_unavailable".to_string()),
+                "ownership could not be verified",
+            )
+        })?;
+        if !access.is_owner {
+            return Ok(Introspection::default());
+        }
+    }
+
+    Ok(Introspection {
+        active: true,
+        scope: Some(SCOPES.join(" ")),
+        client_id: Some(grant.client_id),
+        token_type: Some("Bearer".to_string()),
+        exp: Some(grant.expires_at.timestamp()),
+        sub: Some(additional_claims.account),
+        contract: Some(additional_claims.contract),
+        chain_id: Some(additional_claims.chain_id),
+        token_id: additional_claims.token_id,
+    })
+}
+
+#[post("/introspect", data = "<introspect_data>")]
+pub async fn default_introspect_endpoint(
+    config: &State<Config>,
+    store: &State<Store>,
+    basic_auth: BasicAuth,
+    introspect_data: Form<IntrospectData>,
+) -> Result<Json<Introspection>, TokenError> {
+    introspect_endpoint(config, store, basic_auth, "default".into(), introspect_data).await
+}
+
+#[post("/<realm>/introspect", data = "<introspect_data>")]
+pub async fn introspect_endpoint(
+    config: &State<Config>,
+    store: &State<Store>,
+    basic_auth: BasicAuth,
+    realm: String,
+    introspect_data: Form<IntrospectData>,
+) -> Result<Json<Introspection>, TokenError> {
+    let introspect_data = introspect_data.into_inner();
+
+    let credentials = ClientCredentials {
+        basic: basic_auth.0,
+        client_id: introspect_data.client_id,
+        client_secret: introspect_data.client_secret,
+        client_assertion_type: introspect_data.client_assertion_type,
+        client_assertion: introspect_data.client_assertion,
+    };
+    let client_id = authenticate_client(config, store, &realm, &credentials)
+        .await
+        .map_err(|err| token_error(CoreErrorResponseType::InvalidClient, &err))?;
+    // public clients have no credentials to protect the introspection
+    if auth_method(get_client(config, &client_id).unwrap()) == "none" {
+        return Err(token_error(
+            CoreErrorResponseType::InvalidClient,
+            "client credentials required",
+        ));
+    }
+
+    let token = introspect_data
+        .token
+        .ok_or_else(|| token_error(CoreErrorResponseType::InvalidRequest, "token missing"))?;
+    introspect(
+        config,
+        store,
+        &token,
+        introspect_data.check_ownership.unwrap_or(false),
+    )
+    .await
+    .map(Json)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::rocket;
+    use crate::tests::{issue_code, update_session};
+    use chrono::{Duration, Utc};
+    use rocket::http::{ContentType, Header, Status};
+    use rocket::local::blocking::{Client, LocalResponse};
+    use serde_json::Value;
+
+    fn access_token(client: &Client) -> String {
+        let code = issue_code(client, "foo", "https://example.com");
+        let response = client
+            .post("/okt/token")
+            .header(ContentType::Form)
+            .body(format!(
+                "grant_type=authorization_code&code={}&redirect_uri=https://example.com&client_id=foo",
+                code
+            ))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let token = response.into_json::<Value>().unwrap();
+        assert!(token.get("expires_in").is_some());
+        token
+            .get("access_token")
+            .unwrap()
+            .as_str()
+            .unwrap()
+            .to_string()
+    }
+
+    fn introspect<'c>(client: &'c Client, body: String) -> LocalResponse<'c> {
+        client
+            .post("/okt/introspect")
+            .header(ContentType::Form)
+            .header(Header::new(
+                "Authorization",
+                format!("Basic {}", base64::encode("confidential:secret")),
+            ))
+            .body(body)
+            .dispatch()
+    }
+
+    #[test]
+    fn introspect_access_token() {
+        let client = Client::tracked(rocket()).expect("valid rocket instance");
+        let access_token = access_token(&client);
+
+        let response = introspect(&client, format!("token={}", access_token));
+        assert_eq!(response.status(), Status::Ok);
+        let introspection = response.into_json::<Value>().unwrap();
+        assert_eq!(introspection.get("active").unwrap(), true);
+        assert_eq!(introspection.get("client_id").unwrap(), "foo");
+        assert_eq!(introspection.get("scope").unwrap(), "openid email");
+        assert_eq!(
+            introspection.get("sub").unwrap(),
+            "0x9c9e8eabd947658bdb713e0d3ebfe56860abdb8d"
+        );
+        assert_eq!(
+            introspection.get("contract").unwrap(),
+            "0x886B6781CD7dF75d8440Aba84216b2671AEFf9A4"
+        );
+        assert_eq!(introspection.get("chain_id").unwrap(), 65);
+        assert!(introspection.get("exp").unwrap().as_i64().unwrap() > Utc::now().timestamp());
+
+        // the test claims have no node, so the ownership can not be checked
+        let response = introspect(
+            &client,
+            format!("token={}&check_ownership=true", access_token),
+        );
+        assert_eq!(response.status(), Status::ServiceUnavailable);
+
+        update_session(&client, &access_token, |session| {
+            session.grant.expires_at = Utc::now() - Duration::seconds(1);
+        });
+        let response = introspect(&client, format!("token={}", access_token));
+        assert_eq!(
+            response.into_json::<Value>().unwrap(),
+            serde_json::json!({"active": false})
+        );
+
+        let response = introspect(&client, "token=invalid".to_string());
+        assert_eq!(
+            response.into_json::<Value>().unwrap(),
+            serde_json::json!({"active": false})
+        );
+    }
+
+    #[test]
+    fn introspect_requires_client_credentials() {
+        let client = Client::tracked(rocket()).expect("valid rocket instance");
+        let access_token = access_token(&client);
+
+        let response = client
+            .post("/okt/introspect")
+            .header(ContentType::Form)
+            .body(format!("token={}&client_id=foo", access_token))
+            .dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+
+        let response = client
+            .post("/okt/introspect")
+            .header(ContentType::Form)
+            .body(format!(
+                "token={}&client_id=confidential&client_secret=wrong",
+                access_token
+            ))
+            .dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+    }
+}
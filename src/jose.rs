@@ -0,0 +1,230 @@
+use openidconnect::core::CoreJsonWebKeyUse;
+use openidconnect::{
+    JsonWebKey, JsonWebKeyId, JsonWebKeySet, JsonWebKeyType, JweContentEncryptionAlgorithm,
+    JwsSigningAlgorithm, SignatureVerificationError,
+};
+use ring::digest;
+use ring::signature::{self, RsaPublicKeyComponents, UnparsedPublicKey};
+use serde::{Deserialize, Serialize};
+
+/// The algorithms signing id tokens and JWT access tokens.
+///
+/// openidconnect has no EdDSA, so the provider uses its own JOSE types for its own tokens.
+#[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+pub enum NftJwsSigningAlgorithm {
+    #[serde(rename = "RS256")]
+    RsaSsaPkcs1V15Sha256,
+    #[serde(rename = "ES256")]
+    EcdsaP256Sha256,
+    #[serde(rename = "EdDSA")]
+    EdDsa,
+}
+
+impl JwsSigningAlgorithm<NftJsonWebKeyType> for NftJwsSigningAlgorithm {
+    fn key_type(&self) -> Option<NftJsonWebKeyType> {
+        Some(match self {
+            NftJwsSigningAlgorithm::RsaSsaPkcs1V15Sha256 => NftJsonWebKeyType::RSA,
+            NftJwsSigningAlgorithm::EcdsaP256Sha256 => NftJsonWebKeyType::EllipticCurve,
+            NftJwsSigningAlgorithm::EdDsa => NftJsonWebKeyType::OctetKeyPair,
+        })
+    }
+
+    fn uses_shared_secret(&self) -> bool {
+        false
+    }
+
+    // the at_hash of EdDSA uses SHA-512, as the hash of Ed25519
+    fn hash_bytes(&self, bytes: &[u8]) -> Result<Vec<u8>, String> {
+        let algorithm = match self {
+            NftJwsSigningAlgorithm::EdDsa => &digest::SHA512,
+            _ => &digest::SHA256,
+        };
+        Ok(digest::digest(algorithm, bytes).as_ref().to_vec())
+    }
+
+    fn rsa_sha_256() -> Self {
+        NftJwsSigningAlgorithm::RsaSsaPkcs1V15Sha256
+    }
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+pub enum NftJsonWebKeyType {
+    #[serde(rename = "EC")]
+    EllipticCurve,
+    #[serde(rename = "RSA")]
+    RSA,
+    #[serde(rename = "OKP")]
+    OctetKeyPair,
+    #[serde(rename = "oct")]
+    Symmetric,
+}
+
+impl JsonWebKeyType for NftJsonWebKeyType {}
+
+/// The content encryption algorithms of encrypted tokens.
+#[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+pub enum NftJweContentEncryptionAlgorithm {
+    #[serde(rename = "A128GCM")]
+    Aes128Gcm,
+    #[serde(rename = "A256GCM")]
+    Aes256Gcm,
+}
+
+impl JweContentEncryptionAlgorithm<NftJsonWebKeyType> for NftJweContentEncryptionAlgorithm {
+    fn key_type(&self) -> Result<NftJsonWebKeyType, String> {
+        Ok(NftJsonWebKeyType::Symmetric)
+    }
+}
+
+/// A public key of the provider, as published at `/jwk`.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct NftJsonWebKey {
+    pub kty: NftJsonWebKeyType,
+    #[serde(rename = "use", skip_serializing_if = "Option::is_none")]
+    pub use_: Option<CoreJsonWebKeyUse>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kid: Option<JsonWebKeyId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alg: Option<NftJwsSigningAlgorithm>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub n: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub e: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub crv: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub x: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub y: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub k: Option<String>,
+}
+
+pub type NftJsonWebKeySet =
+    JsonWebKeySet<NftJwsSigningAlgorithm, NftJsonWebKeyType, CoreJsonWebKeyUse, NftJsonWebKey>;
+
+pub fn encode(value: &[u8]) -> String {
+    base64::encode_config(value, base64::URL_SAFE_NO_PAD)
+}
+
+fn decode(value: &Option<String>) -> Result<Vec<u8>, SignatureVerificationError> {
+    value
+        .as_ref()
+        .and_then(|value| base64::decode_config(value, base64::URL_SAFE_NO_PAD).ok())
+        .ok_or_else(|| SignatureVerificationError::InvalidKey("malformed key".to_string()))
+}
+
+impl NftJsonWebKey {
+    fn new(kty: NftJsonWebKeyType, kid: Option<JsonWebKeyId>, alg: NftJwsSigningAlgorithm) -> Self {
+        NftJsonWebKey {
+            kty,
+            use_: Some(CoreJsonWebKeyUse::Signature),
+            kid,
+            alg: Some(alg),
+            n: None,
+            e: None,
+            crv: None,
+            x: None,
+            y: None,
+            k: None,
+        }
+    }
+
+    pub fn new_rsa(n: &[u8], e: &[u8], kid: Option<JsonWebKeyId>) -> Self {
+        NftJsonWebKey {
+            n: Some(encode(n)),
+            e: Some(encode(e)),
+            ..Self::new(
+                NftJsonWebKeyType::RSA,
+                kid,
+                NftJwsSigningAlgorithm::RsaSsaPkcs1V15Sha256,
+            )
+        }
+    }
+
+    /// A P-256 key of its uncompressed point.
+    pub fn new_p256(point: &[u8], kid: Option<JsonWebKeyId>) -> Self {
+        NftJsonWebKey {
+            crv: Some("P-256".to_string()),
+            x: Some(encode(&point[1..33])),
+            y: Some(encode(&point[33..])),
+            ..Self::new(
+                NftJsonWebKeyType::EllipticCurve,
+                kid,
+                NftJwsSigningAlgorithm::EcdsaP256Sha256,
+            )
+        }
+    }
+
+    pub fn new_ed25519(public_key: &[u8], kid: Option<JsonWebKeyId>) -> Self {
+        NftJsonWebKey {
+            crv: Some("Ed25519".to_string()),
+            x: Some(encode(public_key)),
+            ..Self::new(
+                NftJsonWebKeyType::OctetKeyPair,
+                kid,
+                NftJwsSigningAlgorithm::EdDsa,
+            )
+        }
+    }
+}
+
+impl JsonWebKey<NftJwsSigningAlgorithm, NftJsonWebKeyType, CoreJsonWebKeyUse> for NftJsonWebKey {
+    fn key_id(&self) -> Option<&JsonWebKeyId> {
+        self.kid.as_ref()
+    }
+
+    fn key_type(&self) -> &NftJsonWebKeyType {
+        &self.kty
+    }
+
+    fn key_use(&self) -> Option<&CoreJsonWebKeyUse> {
+        self.use_.as_ref()
+    }
+
+    fn new_symmetric(key: Vec<u8>) -> Self {
+        NftJsonWebKey {
+            kty: NftJsonWebKeyType::Symmetric,
+            use_: None,
+            kid: None,
+            alg: None,
+            n: None,
+            e: None,
+            crv: None,
+            x: None,
+            y: None,
+            k: Some(encode(&key)),
+        }
+    }
+
+    fn verify_signature(
+        &self,
+        signature_alg: &NftJwsSigningAlgorithm,
+        message: &[u8],
+        signature: &[u8],
+    ) -> Result<(), SignatureVerificationError> {
+        if signature_alg.key_type().as_ref() != Some(&self.kty) {
+            return Err(SignatureVerificationError::InvalidKey(
+                "key type does not match the signature algorithm".to_string(),
+            ));
+        }
+        let verified = match signature_alg {
+            NftJwsSigningAlgorithm::RsaSsaPkcs1V15Sha256 => RsaPublicKeyComponents {
+                n: decode(&self.n)?,
+                e: decode(&self.e)?,
+            }
+            .verify(&signature::RSA_PKCS1_2048_8192_SHA256, message, signature),
+            NftJwsSigningAlgorithm::EcdsaP256Sha256 => {
+                let point = [vec![4], decode(&self.x)?, decode(&self.y)?].concat();
+                UnparsedPublicKey::new(&signature::ECDSA_P256_SHA256_FIXED, point)
+                    .verify(message, signature)
+            }
+            NftJwsSigningAlgorithm::EdDsa => {
+                UnparsedPublicKey::new(&signature::ED25519, decode(&self.x)?)
+                    .verify(message, signature)
+            }
+        };
+        verified.map_err(|_| SignatureVerificationError::CryptoError("bad signature".to_string()))
+    }
+}
@@ -0,0 +1,398 @@
+use openidconnect::core::CoreJweKeyManagementAlgorithm;
+use rand::rngs::OsRng;
+use ring::aead::{self, Aad, LessSafeKey, Nonce, UnboundKey};
+use ring::agreement::{self, EphemeralPrivateKey, UnparsedPublicKey};
+use ring::digest;
+use ring::rand::{SecureRandom, SystemRandom};
+use rsa::{BigUint, PaddingScheme, PublicKey, RsaPublicKey};
+use serde::Deserialize;
+
+use crate::client_auth::auth_method;
+use crate::config::{ClientConfig, Config};
+use crate::jose::{encode, NftJweContentEncryptionAlgorithm};
+
+/// The key management algorithms of encrypted id tokens and userinfo responses.
+pub const KEY_MANAGEMENT_ALGORITHMS: [CoreJweKeyManagementAlgorithm; 3] = [
+    CoreJweKeyManagementAlgorithm::RsaOaep,
+    CoreJweKeyManagementAlgorithm::RsaOaepSha256,
+    CoreJweKeyManagementAlgorithm::EcdhEs,
+];
+
+/// The content encryption algorithms of encrypted id tokens and userinfo responses.
+pub const CONTENT_ENCRYPTION_ALGORITHMS: [NftJweContentEncryptionAlgorithm; 2] = [
+    NftJweContentEncryptionAlgorithm::Aes128Gcm,
+    NftJweContentEncryptionAlgorithm::Aes256Gcm,
+];
+
+/// The public parameters of a key in the `jwks` of a client.
+#[derive(Debug, Deserialize)]
+struct ClientKey {
+    kty: String,
+    #[serde(rename = "use")]
+    use_: Option<String>,
+    kid: Option<String>,
+    crv: Option<String>,
+    n: Option<String>,
+    e: Option<String>,
+    x: Option<String>,
+    y: Option<String>,
+}
+
+fn decode(value: &Option<String>) -> Result<Vec<u8>, String> {
+    value
+        .as_ref()
+        .and_then(|value| base64::decode_config(value, base64::URL_SAFE_NO_PAD).ok())
+        .ok_or_else(|| "malformed encryption key".to_string())
+}
+
+/// Finds the key of the client to encrypt with `alg`, the first one of its type with `use` `enc`.
+///
+/// Keys without `use` are taken only if there is none with `use` `enc` and the client does not
+/// authenticate with `private_key_jwt`, whose signing keys are in the same `jwks`.
+fn client_key(
+    client: &ClientConfig,
+    alg: &CoreJweKeyManagementAlgorithm,
+) -> Result<ClientKey, String> {
+    let (kty, crv) = match alg {
+        CoreJweKeyManagementAlgorithm::RsaOaep | CoreJweKeyManagementAlgorithm::RsaOaepSha256 => {
+            ("RSA", None)
+        }
+        CoreJweKeyManagementAlgorithm::EcdhEs => ("EC", Some("P-256")),
+        alg => return Err(format!("unsupported key management algorithm {:?}", alg)),
+    };
+    let any_use = auth_method(client) != "private_key_jwt";
+    client
+        .jwks
+        .iter()
+        .flatten()
+        .filter_map(|key| serde_json::to_value(key).ok())
+        .filter_map(|key| serde_json::from_value::<ClientKey>(key).ok())
+        .filter(|key| {
+            key.kty == kty
+                && key.crv.as_deref() == crv
+                && match key.use_.as_deref() {
+                    Some(key_use) => key_use == "enc",
+                    None => any_use,
+                }
+        })
+        .min_by_key(|key| key.use_.is_none())
+        .ok_or_else(|| format!("no {} encryption key in the jwks of the client", kty))
+}
+
+/// The content encryption key for `enc` agreed with the client key by ECDH-ES, returning the
+/// ephemeral public key, as in RFC 7518 section 4.6.
+fn agree_key(
+    key: &ClientKey,
+    enc: &NftJweContentEncryptionAlgorithm,
+    rng: &SystemRandom,
+) -> Result<(Vec<u8>, Vec<u8>), String> {
+    let peer_point = [vec![4], decode(&key.x)?, decode(&key.y)?].concat();
+    let private_key = EphemeralPrivateKey::generate(&agreement::ECDH_P256, rng)
+        .map_err(|_| "key agreement failed".to_string())?;
+    let public_key = private_key
+        .compute_public_key()
+        .map_err(|_| "key agreement failed".to_string())?;
+    let cek = agreement::agree_ephemeral(
+        private_key,
+        &UnparsedPublicKey::new(&agreement::ECDH_P256, peer_point),
+        "key agreement failed".to_string(),
+        |shared_secret| Ok(concat_kdf(shared_secret, enc, b"", b"")),
+    )?;
+    Ok((public_key.as_ref().to_vec(), cek))
+}
+
+/// The Concat KDF of NIST SP 800-56A with SHA-256 and the party information `apu` and `apv`,
+/// which nft-login leaves empty.
+fn concat_kdf(
+    shared_secret: &[u8],
+    enc: &NftJweContentEncryptionAlgorithm,
+    apu: &[u8],
+    apv: &[u8],
+) -> Vec<u8> {
+    let algorithm_id = serde_json::to_value(enc).unwrap();
+    let algorithm_id = algorithm_id.as_str().unwrap().as_bytes();
+    let key_len = key_len(enc);
+    let mut context = digest::Context::new(&digest::SHA256);
+    context.update(&1u32.to_be_bytes());
+    context.update(shared_secret);
+    context.update(&(algorithm_id.len() as u32).to_be_bytes());
+    context.update(algorithm_id);
+    context.update(&(apu.len() as u32).to_be_bytes());
+    context.update(apu);
+    context.update(&(apv.len() as u32).to_be_bytes());
+    context.update(apv);
+    context.update(&(key_len as u32 * 8).to_be_bytes());
+    context.finish().as_ref()[..key_len].to_vec()
+}
+
+fn key_len(enc: &NftJweContentEncryptionAlgorithm) -> usize {
+    match enc {
+        NftJweContentEncryptionAlgorithm::Aes128Gcm => 16,
+        NftJweContentEncryptionAlgorithm::Aes256Gcm => 32,
+    }
+}
+
+pub fn aead_algorithm(enc: &NftJweContentEncryptionAlgorithm) -> &'static aead::Algorithm {
+    match enc {
+        NftJweContentEncryptionAlgorithm::Aes128Gcm => &aead::AES_128_GCM,
+        NftJweContentEncryptionAlgorithm::Aes256Gcm => &aead::AES_256_GCM,
+    }
+}
+
+/// Encrypts the payload for the client as a JWE in compact serialization.
+///
+/// `cty` is `JWT` for nested tokens, such as a signed id token.
+pub fn encrypt(
+    client: &ClientConfig,
+    alg: &CoreJweKeyManagementAlgorithm,
+    enc: &NftJweContentEncryptionAlgorithm,
+    cty: Option<&str>,
+    payload: &[u8],
+) -> Result<String, String> {
+    let key = client_key(client, alg)?;
+    let rng = SystemRandom::new();
+    let mut header = serde_json::json!({ "alg": alg, "enc": enc });
+    if let Some(cty) = cty {
+        header["cty"] = cty.into();
+    }
+    if let Some(kid) = &key.kid {
+        header["kid"] = kid.as_str().into();
+    }
+
+    let (encrypted_key, cek) = match alg {
+        CoreJweKeyManagementAlgorithm::EcdhEs => {
+            let (public_key, cek) = agree_key(&key, enc, &rng)?;
+            header["epk"] = serde_json::json!({
+                "kty": "EC",
+                "crv": "P-256",
+                "x": encode(&public_key[1..33]),
+                "y": encode(&public_key[33..]),
+            });
+            (vec![], cek)
+        }
+        _ => {
+            let mut cek = vec![0; key_len(enc)];
+            rng.fill(&mut cek)
+                .map_err(|_| "random generation failed".to_string())?;
+            let public_key = RsaPublicKey::new(
+                BigUint::from_bytes_be(&decode(&key.n)?),
+                BigUint::from_bytes_be(&decode(&key.e)?),
+            )
+            .map_err(|err| format!("invalid encryption key: {}", err))?;
+            let padding = match alg {
+                CoreJweKeyManagementAlgorithm::RsaOaepSha256 => {
+                    PaddingScheme::new_oaep::<sha2::Sha256>()
+                }
+                _ => PaddingScheme::new_oaep::<sha1::Sha1>(),
+            };
+            let encrypted_key = public_key
+                .encrypt(&mut OsRng, padding, &cek)
+                .map_err(|err| format!("encryption failed: {}", err))?;
+            (encrypted_key, cek)
+        }
+    };
+
+    let protected = encode(header.to_string().as_bytes());
+    let mut iv = [0; aead::NONCE_LEN];
+    rng.fill(&mut iv)
+        .map_err(|_| "random generation failed".to_string())?;
+    let key =
+        UnboundKey::new(aead_algorithm(enc), &cek).map_err(|_| "encryption failed".to_string())?;
+    let mut ciphertext = payload.to_vec();
+    let tag = LessSafeKey::new(key)
+        .seal_in_place_separate_tag(
+            Nonce::assume_unique_for_key(iv),
+            Aad::from(protected.as_bytes()),
+            &mut ciphertext,
+        )
+        .map_err(|_| "encryption failed".to_string())?;
+
+    Ok(format!(
+        "{}.{}.{}.{}.{}",
+        protected,
+        encode(&encrypted_key),
+        encode(&iv),
+        encode(&ciphertext),
+        encode(tag.as_ref())
+    ))
+}
+
+/// Encrypts the signed id token for clients with `id_token_encrypted_response_alg`, or else
+/// returns it as is.
+pub fn encrypt_id_token(client: Option<&ClientConfig>, id_token: String) -> Result<String, String> {
+    match client.and_then(|client| Some((client, client.id_token_encryption()?))) {
+        Some((client, (alg, enc))) => encrypt(client, alg, &enc, Some("JWT"), id_token.as_bytes()),
+        None => Ok(id_token),
+    }
+}
+
+/// Checks the encryption of every client, so a misconfigured client fails startup rather than
+/// its logins.
+pub fn check_clients(config: &Config) -> Result<(), String> {
+    for (client_id, client) in &config.clients {
+        let algs = [
+            &client.id_token_encrypted_response_alg,
+            &client.userinfo_encrypted_response_alg,
+        ];
+        for alg in algs.iter().filter_map(|alg| alg.as_ref()) {
+            if !KEY_MANAGEMENT_ALGORITHMS.contains(alg) {
+                return Err(format!(
+                    "unsupported key management algorithm {:?} of client {}",
+                    alg, client_id
+                ));
+            }
+            client_key(client, alg).map_err(|err| format!("{} {}", err, client_id))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::jwe::{decrypt, header, open};
+    use openidconnect::core::CoreJsonWebKey;
+    use rsa::pkcs1::DecodeRsaPrivateKey;
+    use rsa::{PublicKeyParts, RsaPrivateKey};
+
+    fn decode(segment: &str) -> Vec<u8> {
+        base64::decode_config(segment, base64::URL_SAFE_NO_PAD).unwrap()
+    }
+
+    fn client(jwk: serde_json::Value) -> ClientConfig {
+        ClientConfig {
+            jwks: Some(vec![serde_json::from_value::<CoreJsonWebKey>(jwk).unwrap()]),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_rsa_oaep() {
+        let private_key =
+            RsaPrivateKey::from_pkcs1_pem(include_str!("../do-not-use-client.pem")).unwrap();
+        let client = client(serde_json::json!({
+            "kty": "RSA",
+            "use": "enc",
+            "kid": "client",
+            "n": encode(&private_key.n().to_bytes_be()),
+            "e": encode(&private_key.e().to_bytes_be()),
+        }));
+        for alg in [
+            CoreJweKeyManagementAlgorithm::RsaOaep,
+            CoreJweKeyManagementAlgorithm::RsaOaepSha256,
+        ] {
+            for enc in CONTENT_ENCRYPTION_ALGORITHMS {
+                let jwe = encrypt(&client, &alg, &enc, Some("JWT"), b"payload").unwrap();
+                assert_eq!(header(&jwe).get("cty").unwrap(), "JWT");
+                assert_eq!(header(&jwe).get("kid").unwrap(), "client");
+                assert_eq!(decrypt(&jwe), b"payload");
+            }
+        }
+        assert!(encrypt(
+            &client,
+            &CoreJweKeyManagementAlgorithm::EcdhEs,
+            &NftJweContentEncryptionAlgorithm::Aes128Gcm,
+            None,
+            b"payload"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_ecdh_es() {
+        let rng = SystemRandom::new();
+        for enc in CONTENT_ENCRYPTION_ALGORITHMS {
+            let private_key = EphemeralPrivateKey::generate(&agreement::ECDH_P256, &rng).unwrap();
+            let public_key = private_key.compute_public_key().unwrap();
+            let client = client(serde_json::json!({
+                "kty": "EC",
+                "use": "enc",
+                "crv": "P-256",
+                "x": encode(&public_key.as_ref()[1..33]),
+                "y": encode(&public_key.as_ref()[33..]),
+            }));
+            let jwe = encrypt(
+                &client,
+                &CoreJweKeyManagementAlgorithm::EcdhEs,
+                &enc,
+                None,
+                b"payload",
+            )
+            .unwrap();
+
+            let segments: Vec<&str> = jwe.split('.').collect();
+            assert!(segments[1].is_empty());
+            let header = header(&jwe);
+            let epk = header.get("epk").unwrap();
+            let point = [
+                vec![4],
+                decode(epk.get("x").unwrap().as_str().unwrap()),
+                decode(epk.get("y").unwrap().as_str().unwrap()),
+            ]
+            .concat();
+            let cek = agreement::agree_ephemeral(
+                private_key,
+                &UnparsedPublicKey::new(&agreement::ECDH_P256, point),
+                (),
+                |shared_secret| Ok(concat_kdf(shared_secret, &enc, b"", b"")),
+            )
+            .unwrap();
+            assert_eq!(open(&jwe, &cek), b"payload");
+        }
+    }
+
+    #[test]
+    fn test_concat_kdf() {
+        // RFC 7518 appendix C
+        let shared_secret = [
+            158, 86, 217, 29, 129, 113, 53, 211, 114, 131, 66, 131, 191, 132, 38, 156, 251, 49,
+            110, 163, 218, 128, 106, 72, 246, 218, 167, 121, 140, 254, 144, 196,
+        ];
+        assert_eq!(
+            encode(&concat_kdf(
+                &shared_secret,
+                &NftJweContentEncryptionAlgorithm::Aes128Gcm,
+                b"Alice",
+                b"Bob",
+            )),
+            "VqqN6vgjbSBcIijNcacQGg"
+        );
+    }
+
+    #[test]
+    fn test_key_without_use() {
+        let jwk = serde_json::json!({
+            "kty": "EC",
+            "crv": "P-256",
+            "x": "gI0GAILBdu7T53akrFmMyGcsF3n5dO7MmwNBHKW5SV0",
+            "y": "SLW_xSffzlPWrHEVI30DHM_4egVwt3NQqeUD7nMFpps",
+        });
+        let mut client = client(jwk);
+        client.token_endpoint_auth_method = Some("none".to_string());
+        assert!(client_key(&client, &CoreJweKeyManagementAlgorithm::EcdhEs).is_ok());
+
+        client.token_endpoint_auth_method = Some("private_key_jwt".to_string());
+        assert!(client_key(&client, &CoreJweKeyManagementAlgorithm::EcdhEs).is_err());
+    }
+
+    #[test]
+    fn test_check_clients() {
+        let mut config = Config::default();
+        config.clients.insert(
+            "client".into(),
+            ClientConfig {
+                userinfo_encrypted_response_alg: Some(CoreJweKeyManagementAlgorithm::RsaOaep),
+                ..Default::default()
+            },
+        );
+        assert!(check_clients(&config).is_err());
+        config.clients.insert(
+            "client".into(),
+            ClientConfig {
+                id_token_encrypted_response_alg: Some(CoreJweKeyManagementAlgorithm::Direct),
+                ..Default::default()
+            },
+        );
+        assert!(check_clients(&config).is_err());
+    }
+}
@@ -4,21 +4,33 @@ extern crate rocket;
 #[macro_use]
 extern crate rocket_include_static_resources;
 
-use claims::ClaimsMutex;
 use rocket::fairing::{Fairing, Info, Kind};
 use rocket::http::Header;
 use rocket::State;
 use rocket::{Request, Response};
 use rocket_include_static_resources::{EtagIfNoneMatch, StaticContextManager, StaticResponse};
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
-
-use openidconnect::core::{CoreJsonWebKeySet, CoreRsaPrivateSigningKey};
-use openidconnect::{JsonWebKeyId, PrivateSigningKey};
 
+mod access_token;
 mod authorize;
 mod claims;
+mod client_auth;
 mod config;
+mod eip712;
+mod introspect;
+mod jose;
+mod jwe;
+mod keys;
+mod nonce;
+mod pkce;
+mod policy;
+#[cfg(feature = "redis")]
+mod redis_storage;
+mod refresh;
+mod revoke;
+mod siwe;
+#[cfg(feature = "sql")]
+mod sql_storage;
+mod storage;
 mod tests;
 mod token;
 mod userinfo;
@@ -29,9 +41,12 @@ use config::{
     authorize_well_known, configuration, default_configuration,
     well_known_oauth_authorization_server, Config,
 };
+use introspect::{default_introspect_endpoint, introspect_endpoint};
+use keys::{default_rotate_endpoint, rotate_endpoint, Keys};
+use revoke::{default_revoke_endpoint, revoke_endpoint};
+use storage::{storage_fairing, sweeper_fairing};
 use token::{
     default_post_token_endpoint, default_token_endpoint, post_token_endpoint, token_endpoint,
-    Tokens,
 };
 use userinfo::{
     default_options_userinfo_endpoint, default_userinfo_endpoint, options_userinfo_endpoint,
@@ -64,21 +79,13 @@ fn index(
 }
 
 #[get("/jwk")]
-fn default_jwk(config: &State<Config>) -> String {
-    jwk(config, "".into())
+fn default_jwk(keys: &State<Keys>) -> String {
+    jwk(keys, "default".into())
 }
 
-#[get("/<_realm>/jwk")]
-fn jwk(config: &State<Config>, _realm: String) -> String {
-    let rsa_pem = include_str!("../do-not-use.pem");
-    let jwks = CoreJsonWebKeySet::new(vec![CoreRsaPrivateSigningKey::from_pem(
-        rsa_pem,
-        Some(JsonWebKeyId::new(config.key_id.to_string())),
-    )
-    .expect("Invalid RSA private key")
-    .as_verification_key()]);
-
-    serde_json::to_string(&jwks).unwrap()
+#[get("/<realm>/jwk")]
+fn jwk(keys: &State<Keys>, realm: String) -> String {
+    serde_json::to_string(&keys.key_set(&realm).jwks()).unwrap()
 }
 
 pub struct CORS;
@@ -112,20 +119,14 @@ fn unauthorized() -> String {
 pub fn rocket() -> _ {
     let rocket = rocket::build();
     let figment = rocket.figment();
-    let mut config: Config = figment.extract().expect("config");
-
-    println!("{:?}", config);
+    let config: Config = figment.extract().expect("config");
 
-    config.rsa_pem = Some(include_str!("../do-not-use.pem").to_string());
-
-    let tokens: Tokens = Tokens {
-        muted: Arc::new(Mutex::new(HashMap::new())),
-        bearer: Arc::new(Mutex::new(HashMap::new())),
-    };
+    let keys = Keys::load(&config).expect("signing key");
+    jwe::check_clients(&config).expect("client encryption");
 
-    let claims: ClaimsMutex = ClaimsMutex {
-        standard_claims: Arc::new(Mutex::new(HashMap::new())),
-        additional_claims: Arc::new(Mutex::new(HashMap::new())),
+    let token_routes = match config.get_token_endpoint {
+        true => routes![token_endpoint, default_token_endpoint],
+        false => routes![],
     };
 
     rocket
@@ -136,7 +137,12 @@ pub fn rocket() -> _ {
             "index" => ("static", "index.html"),
         ))
         .attach(CORS)
-        .mount("/", routes![cached_indexjs, cached_indexcss, cached_sitemaptxt])
+        .attach(storage_fairing(&config))
+        .attach(sweeper_fairing(&config))
+        .mount(
+            "/",
+            routes![cached_indexjs, cached_indexcss, cached_sitemaptxt],
+        )
         .mount(
             "/",
             routes![
@@ -144,14 +150,18 @@ pub fn rocket() -> _ {
                 default_index,
                 authorize_endpoint,
                 default_authorize_endpoint,
-                token_endpoint,
-                default_token_endpoint,
                 userinfo_endpoint,
                 default_userinfo_endpoint,
                 options_userinfo_endpoint,
                 default_options_userinfo_endpoint,
                 post_token_endpoint,
                 default_post_token_endpoint,
+                revoke_endpoint,
+                default_revoke_endpoint,
+                introspect_endpoint,
+                default_introspect_endpoint,
+                rotate_endpoint,
+                default_rotate_endpoint,
                 configuration,
                 default_configuration,
                 authorize_well_known,
@@ -160,8 +170,8 @@ pub fn rocket() -> _ {
                 default_jwk
             ],
         )
+        .mount("/", token_routes)
         .manage(config)
-        .manage(tokens)
-        .manage(claims)
+        .manage(keys)
         .register("/", catchers![unauthorized])
 }
@@ -0,0 +1,58 @@
+use chrono::{Duration, Utc};
+use uuid::Uuid;
+
+use crate::storage::Store;
+
+/// Mints a login challenge, which expires after `ttl` seconds.
+pub async fn issue_nonce(store: &Store, ttl: i64) -> Result<String, String> {
+    let nonce = Uuid::new_v4().to_simple().to_string();
+    store
+        .insert_nonce(&nonce, Utc::now() + Duration::seconds(ttl))
+        .await?;
+    Ok(nonce)
+}
+
+/// Removes the nonce, so every issued nonce can be used only once.
+pub async fn consume_nonce(store: &Store, nonce: &str) -> Result<(), String> {
+    match store.remove_nonce(nonce).await? {
+        Some(expires_at) if expires_at > Utc::now() => Ok(()),
+        Some(_) => Err("challenge expired".to_string()),
+        None => Err("challenge unknown or already used".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+    use std::sync::Arc;
+
+    fn store() -> Store {
+        Arc::new(MemoryStorage::default())
+    }
+
+    #[tokio::test]
+    async fn test_consume() {
+        let store = store();
+        let nonce = issue_nonce(&store, 300).await.unwrap();
+        assert!(consume_nonce(&store, &nonce).await.is_ok());
+        assert_eq!(
+            consume_nonce(&store, &nonce).await,
+            Err("challenge unknown or already used".to_string())
+        );
+        assert!(consume_nonce(&store, "unknown").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_expired() {
+        let store = store();
+        store
+            .insert_nonce("expired", Utc::now() - Duration::seconds(1))
+            .await
+            .unwrap();
+        assert_eq!(
+            consume_nonce(&store, "expired").await,
+            Err("challenge expired".to_string())
+        );
+    }
+}
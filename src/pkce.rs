@@ -0,0 +1,84 @@
+use openidconnect::{PkceCodeChallenge, PkceCodeVerifier};
+
+pub const CODE_CHALLENGE_METHODS: [&str; 2] = ["S256", "plain"];
+
+// RFC 7636 section 4.1 allows 43 to 128 unreserved characters.
+fn is_valid_code(code: &str) -> bool {
+    (43..=128).contains(&code.len())
+        && code
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-._~".contains(c))
+}
+
+/// Checks a code challenge of an authorize request, returning its method.
+pub fn validate_code_challenge(
+    code_challenge: &str,
+    code_challenge_method: Option<&str>,
+) -> Result<String, String> {
+    let code_challenge_method = code_challenge_method.unwrap_or("plain");
+    if !CODE_CHALLENGE_METHODS.contains(&code_challenge_method) {
+        return Err("code challenge method not supported".to_string());
+    }
+    if !is_valid_code(code_challenge) {
+        return Err("invalid code challenge".to_string());
+    }
+    Ok(code_challenge_method.to_string())
+}
+
+/// Verifies the code verifier of a token request against the stored challenge.
+pub fn verify_code_verifier(
+    code_challenge: &str,
+    code_challenge_method: &str,
+    code_verifier: &str,
+) -> bool {
+    if !is_valid_code(code_verifier) {
+        return false;
+    }
+    match code_challenge_method {
+        "S256" => {
+            PkceCodeChallenge::from_code_verifier_sha256(&PkceCodeVerifier::new(
+                code_verifier.to_string(),
+            ))
+            .as_str()
+                == code_challenge
+        }
+        "plain" => code_verifier == code_challenge,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The example of RFC 7636 appendix B.
+    const VERIFIER: &str = "dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk";
+    const CHALLENGE: &str = "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM";
+
+    #[test]
+    fn test_validate_code_challenge() {
+        assert_eq!(
+            validate_code_challenge(CHALLENGE, Some("S256")),
+            Ok("S256".to_string())
+        );
+        assert_eq!(
+            validate_code_challenge(CHALLENGE, None),
+            Ok("plain".to_string())
+        );
+        assert!(validate_code_challenge(CHALLENGE, Some("S512")).is_err());
+        assert!(validate_code_challenge("short", Some("S256")).is_err());
+    }
+
+    #[test]
+    fn test_verify_code_verifier() {
+        assert!(verify_code_verifier(CHALLENGE, "S256", VERIFIER));
+        assert!(!verify_code_verifier(CHALLENGE, "plain", VERIFIER));
+        assert!(verify_code_verifier(VERIFIER, "plain", VERIFIER));
+        assert!(!verify_code_verifier(CHALLENGE, "S256", "short"));
+        assert!(!verify_code_verifier(
+            CHALLENGE,
+            "S256",
+            &VERIFIER.replace('d', "e")
+        ));
+    }
+}
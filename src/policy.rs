@@ -0,0 +1,261 @@
+use futures::future::join_all;
+use serde::{Deserialize, Serialize};
+
+use crate::config::{get_node, ClientConfig, Config};
+use crate::web3::{is_nft_owner_of, parse_token_ids, OwnershipCondition, TokenStandard};
+
+/// An ownership condition of an access policy, evaluated on its own chain.
+#[derive(Debug, Default, PartialEq, Clone, Serialize, Deserialize)]
+pub struct PolicyCondition {
+    pub id: Option<String>,
+    pub contract: String,
+    pub chain: Option<String>,
+    #[serde(default)]
+    pub token_standard: TokenStandard,
+    #[serde(default)]
+    pub token_ids: Vec<String>,
+    pub token_id: Option<String>,
+    pub min_balance: Option<String>,
+}
+
+/// A per-client access policy combining ownership conditions.
+///
+/// Empty `any_of` and `all_of` lists are never satisfied.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Policy {
+    AnyOf(Vec<Policy>),
+    AllOf(Vec<Policy>),
+    Not(Box<Policy>),
+    Condition(PolicyCondition),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct PolicyResult {
+    pub is_satisfied: bool,
+    pub satisfied: Vec<String>,
+}
+
+impl PolicyCondition {
+    pub fn chain(&self, realm: &str) -> String {
+        self.chain.clone().unwrap_or_else(|| realm.to_string())
+    }
+
+    pub fn id(&self, realm: &str) -> String {
+        self.id
+            .clone()
+            .unwrap_or_else(|| format!("{}:{}", self.chain(realm), self.contract))
+    }
+
+    pub fn ownership_condition(&self) -> Result<OwnershipCondition, String> {
+        let token_ids = match self.token_ids.is_empty() {
+            true => Vec::new(),
+            false => parse_token_ids(&self.token_ids.join(","))?,
+        };
+        let token_id = match &self.token_id {
+            Some(token_id) => match parse_token_ids(token_id)?[..] {
+                [token_id] => Some(token_id),
+                _ => return Err("invalid token id".to_string()),
+            },
+            None => None,
+        };
+        Ok(OwnershipCondition {
+            contract: self.contract.clone(),
+            token_standard: self.token_standard,
+            token_ids,
+            token_id,
+            min_balance: self.min_balance.clone(),
+        })
+    }
+}
+
+impl Policy {
+    /// All conditions of the policy in depth-first order.
+    pub fn conditions(&self) -> Vec<&PolicyCondition> {
+        match self {
+            Policy::AnyOf(policies) | Policy::AllOf(policies) => policies
+                .iter()
+                .flat_map(|policy| policy.conditions())
+                .collect(),
+            Policy::Not(policy) => policy.conditions(),
+            Policy::Condition(condition) => vec![condition],
+        }
+    }
+
+    /// Combines the results of the conditions, given in depth-first order.
+    pub fn resolve(&self, results: &mut impl Iterator<Item = bool>) -> bool {
+        match self {
+            Policy::AnyOf(policies) => {
+                let results: Vec<bool> = policies.iter().map(|p| p.resolve(results)).collect();
+                results.iter().any(|result| *result)
+            }
+            Policy::AllOf(policies) => {
+                let results: Vec<bool> = policies.iter().map(|p| p.resolve(results)).collect();
+                !results.is_empty() && results.iter().all(|result| *result)
+            }
+            Policy::Not(policy) => !policy.resolve(results),
+            Policy::Condition(_) => results.next().unwrap_or(false),
+        }
+    }
+}
+
+/// Evaluates all conditions of the policy concurrently.
+///
+/// Fails if any condition could not be evaluated, so a `not` never turns an error into access.
+pub async fn evaluate_policy(
+    config: &Config,
+    policy: &Policy,
+    realm: &str,
+    account: &str,
+) -> Result<PolicyResult, String> {
+    let conditions = policy.conditions();
+    let ownership_conditions = conditions
+        .iter()
+        .map(|condition| condition.ownership_condition())
+        .collect::<Result<Vec<OwnershipCondition>, String>>()?;
+
+    let ownerships = join_all(conditions.iter().zip(ownership_conditions.iter()).map(
+        |(condition, ownership_condition)| {
+            is_nft_owner_of(
+                ownership_condition,
+                account.to_string(),
+                get_node(config, &condition.chain(realm)),
+            )
+        },
+    ))
+    .await
+    .into_iter()
+    .collect::<web3::Result<Vec<_>>>()
+    .map_err(|err| err.to_string())?;
+
+    let results: Vec<bool> = ownerships
+        .iter()
+        .map(|ownership| ownership.is_owner)
+        .collect();
+
+    Ok(PolicyResult {
+        is_satisfied: policy.resolve(&mut results.iter().cloned()),
+        satisfied: conditions
+            .iter()
+            .zip(results.iter())
+            .filter(|(_, result)| **result)
+            .map(|(condition, _)| condition.id(realm))
+            .collect(),
+    })
+}
+
+/// The outcome of the access check of a client.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Access {
+    pub is_owner: bool,
+    pub balance: String,
+    pub satisfied: Vec<String>,
+}
+
+/// Checks the policy of the client, or the single ownership condition if it has none.
+pub async fn verify_access(
+    config: &Config,
+    client: &ClientConfig,
+    condition: &OwnershipCondition,
+    realm: &str,
+    account: &str,
+    node_provider: &str,
+) -> Result<Access, String> {
+    match &client.policy {
+        Some(policy) => {
+            let result = evaluate_policy(config, policy, realm, account).await?;
+            Ok(Access {
+                is_owner: result.is_satisfied,
+                balance: String::new(),
+                satisfied: result.satisfied,
+            })
+        }
+        None => {
+            let ownership =
+                is_nft_owner_of(condition, account.to_string(), node_provider.to_string())
+                    .await
+                    .map_err(|err| err.to_string())?;
+            Ok(Access {
+                is_owner: ownership.is_owner,
+                balance: ownership.balance.to_string(),
+                satisfied: Vec::new(),
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::figment::providers::{Format, Toml};
+    use rocket::figment::Figment;
+
+    fn condition(contract: &str) -> Policy {
+        Policy::Condition(PolicyCondition {
+            contract: contract.to_string(),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_deserialize() {
+        let policy: Policy = Figment::from(Toml::string(
+            r#"
+            any_of = [
+                { condition = { id = "pass", contract = "0x886B6781CD7dF75d8440Aba84216b2671AEFf9A4", chain = "okt" } },
+                { all_of = [
+                    { condition = { contract = "0xa0d4E5CdD89330ef9d0d1071247909882f0562eA", token_standard = "erc20", min_balance = "100" } },
+                    { not = { condition = { contract = "0x420d2a6E87D87992EB01e5BFe762B3F437dBfD85", token_id = "42" } } },
+                ] },
+            ]
+            "#,
+        ))
+        .extract()
+        .unwrap();
+
+        let conditions = policy.conditions();
+        assert_eq!(conditions.len(), 3);
+        assert_eq!(conditions[0].id("kovan"), "pass");
+        assert_eq!(conditions[0].chain("kovan"), "okt");
+        assert_eq!(
+            conditions[1].id("kovan"),
+            "kovan:0xa0d4E5CdD89330ef9d0d1071247909882f0562eA"
+        );
+        assert_eq!(conditions[1].token_standard, TokenStandard::Erc20);
+        assert_eq!(conditions[2].token_id, Some("42".to_string()));
+    }
+
+    #[test]
+    fn test_resolve() {
+        let policy = Policy::AnyOf(vec![
+            condition("a"),
+            Policy::AllOf(vec![condition("b"), Policy::Not(Box::new(condition("c")))]),
+        ]);
+        assert!(policy.resolve(&mut vec![true, false, false].into_iter()));
+        assert!(policy.resolve(&mut vec![false, true, false].into_iter()));
+        assert!(!policy.resolve(&mut vec![false, true, true].into_iter()));
+        assert!(!policy.resolve(&mut vec![false, false, false].into_iter()));
+
+        assert!(!Policy::AllOf(vec![]).resolve(&mut vec![].into_iter()));
+        assert!(!Policy::AnyOf(vec![]).resolve(&mut vec![].into_iter()));
+    }
+
+    #[test]
+    fn test_ownership_condition() {
+        let condition = PolicyCondition {
+            contract: "0x886B6781CD7dF75d8440Aba84216b2671AEFf9A4".to_string(),
+            token_standard: TokenStandard::Erc1155,
+            token_ids: vec!["1".to_string(), "2".to_string()],
+            ..Default::default()
+        };
+        let ownership_condition = condition.ownership_condition().unwrap();
+        assert_eq!(ownership_condition.token_ids.len(), 2);
+        assert_eq!(ownership_condition.token_id, None);
+
+        let condition = PolicyCondition {
+            token_id: Some("foo".to_string()),
+            ..Default::default()
+        };
+        assert!(condition.ownership_condition().is_err());
+    }
+}
@@ -0,0 +1,300 @@
+use chrono::{DateTime, TimeZone, Utc};
+use redis::aio::ConnectionManager;
+use redis::AsyncCommands;
+
+use crate::refresh::RefreshGrant;
+use crate::storage::{Session, Storage};
+use crate::token::Grant;
+
+/// Keeps the state in Redis, so it is shared between replicas behind a load balancer.
+///
+/// Every key expires together with its entry.
+pub struct RedisStorage {
+    connection: ConnectionManager,
+}
+
+fn redis_error(error: redis::RedisError) -> String {
+    format!("storage error: {}", error)
+}
+
+fn to_json<T: serde::Serialize>(value: &T) -> Result<String, String> {
+    serde_json::to_string(value).map_err(|err| format!("storage error: {}", err))
+}
+
+fn from_json<T: serde::de::DeserializeOwned>(value: Option<String>) -> Result<Option<T>, String> {
+    value
+        .map(|value| serde_json::from_str(&value).map_err(|err| format!("storage error: {}", err)))
+        .transpose()
+}
+
+/// The seconds until `expires_at`, at least one as redis rejects other expiries.
+fn ttl(expires_at: &DateTime<Utc>) -> usize {
+    (*expires_at - Utc::now()).num_seconds().max(1) as usize
+}
+
+fn code_key(code: &str) -> String {
+    format!("nft-login:code:{}", code)
+}
+
+fn redeemed_key(code: &str) -> String {
+    format!("nft-login:code:{}:redeemed", code)
+}
+
+fn session_key(access_token: &str) -> String {
+    format!("nft-login:session:{}", access_token)
+}
+
+fn refresh_token_key(refresh_token: &str) -> String {
+    format!("nft-login:refresh_token:{}", refresh_token)
+}
+
+fn nonce_key(nonce: &str) -> String {
+    format!("nft-login:nonce:{}", nonce)
+}
+
+fn jti_key(jti: &str) -> String {
+    format!("nft-login:jti:{}", jti)
+}
+
+impl RedisStorage {
+    pub async fn connect(url: &str) -> Result<Self, String> {
+        let client = redis::Client::open(url).map_err(redis_error)?;
+        let connection = ConnectionManager::new(client).await.map_err(redis_error)?;
+        Ok(RedisStorage { connection })
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<String>, String> {
+        self.connection.clone().get(key).await.map_err(redis_error)
+    }
+
+    async fn set(
+        &self,
+        key: &str,
+        value: String,
+        expires_at: &DateTime<Utc>,
+    ) -> Result<(), String> {
+        self.connection
+            .clone()
+            .set_ex(key, value, ttl(expires_at))
+            .await
+            .map_err(redis_error)
+    }
+
+    /// Gets and deletes the key in one transaction, so only one caller receives the value.
+    async fn take(&self, key: &str) -> Result<Option<String>, String> {
+        let (value, _): (Option<String>, i32) = redis::pipe()
+            .atomic()
+            .get(key)
+            .del(key)
+            .query_async(&mut self.connection.clone())
+            .await
+            .map_err(redis_error)?;
+        Ok(value)
+    }
+}
+
+#[rocket::async_trait]
+impl Storage for RedisStorage {
+    async fn insert_code(&self, code: &str, grant: Grant) -> Result<(), String> {
+        let ttl = ttl(&grant.expires_at);
+        let mut pipe = redis::pipe();
+        pipe.atomic()
+            .set_ex(code_key(code), to_json(&grant)?, ttl)
+            .ignore();
+        if grant.redeemed {
+            pipe.set_ex(redeemed_key(code), 1, ttl).ignore();
+        } else {
+            pipe.del(redeemed_key(code)).ignore();
+        }
+        pipe.query_async(&mut self.connection.clone())
+            .await
+            .map_err(redis_error)
+    }
+
+    async fn get_code(&self, code: &str) -> Result<Option<Grant>, String> {
+        let (grant, redeemed): (Option<String>, bool) = redis::pipe()
+            .get(code_key(code))
+            .exists(redeemed_key(code))
+            .query_async(&mut self.connection.clone())
+            .await
+            .map_err(redis_error)?;
+        Ok(from_json::<Grant>(grant)?.map(|grant| Grant {
+            redeemed: grant.redeemed || redeemed,
+            ..grant
+        }))
+    }
+
+    async fn redeem_code(&self, code: &str) -> Result<bool, String> {
+        let grant = match self.get_code(code).await? {
+            Some(grant) if !grant.redeemed => grant,
+            _ => return Ok(false),
+        };
+        // only the first SET NX of the redeemed marker succeeds
+        let redeemed: Option<String> = redis::cmd("SET")
+            .arg(redeemed_key(code))
+            .arg(1)
+            .arg("NX")
+            .arg("EX")
+            .arg(ttl(&grant.expires_at))
+            .query_async(&mut self.connection.clone())
+            .await
+            .map_err(redis_error)?;
+        Ok(redeemed.is_some())
+    }
+
+    async fn remove_code(&self, code: &str) -> Result<(), String> {
+        self.connection
+            .clone()
+            .del(&[code_key(code), redeemed_key(code)])
+            .await
+            .map_err(redis_error)
+    }
+
+    async fn insert_session(&self, access_token: &str, session: Session) -> Result<(), String> {
+        self.set(
+            &session_key(access_token),
+            to_json(&session)?,
+            &session.grant.expires_at,
+        )
+        .await
+    }
+
+    async fn get_session(&self, access_token: &str) -> Result<Option<Session>, String> {
+        from_json(self.get(&session_key(access_token)).await?)
+    }
+
+    async fn remove_session(&self, access_token: &str) -> Result<Option<Session>, String> {
+        from_json(self.take(&session_key(access_token)).await?)
+    }
+
+    async fn insert_refresh_token(
+        &self,
+        refresh_token: &str,
+        grant: RefreshGrant,
+    ) -> Result<(), String> {
+        self.set(
+            &refresh_token_key(refresh_token),
+            to_json(&grant)?,
+            &grant.expires_at,
+        )
+        .await
+    }
+
+    async fn get_refresh_token(&self, refresh_token: &str) -> Result<Option<RefreshGrant>, String> {
+        from_json(self.get(&refresh_token_key(refresh_token)).await?)
+    }
+
+    async fn remove_refresh_token(
+        &self,
+        refresh_token: &str,
+    ) -> Result<Option<RefreshGrant>, String> {
+        from_json(self.take(&refresh_token_key(refresh_token)).await?)
+    }
+
+    async fn insert_nonce(&self, nonce: &str, expires_at: DateTime<Utc>) -> Result<(), String> {
+        self.set(
+            &nonce_key(nonce),
+            expires_at.timestamp().to_string(),
+            &expires_at,
+        )
+        .await
+    }
+
+    async fn remove_nonce(&self, nonce: &str) -> Result<Option<DateTime<Utc>>, String> {
+        match self.take(&nonce_key(nonce)).await? {
+            Some(timestamp) => timestamp
+                .parse()
+                .map(|timestamp| Some(Utc.timestamp(timestamp, 0)))
+                .map_err(|_| format!("storage error: invalid nonce expiry {}", timestamp)),
+            None => Ok(None),
+        }
+    }
+
+    async fn insert_jti(&self, jti: &str, expires_at: DateTime<Utc>) -> Result<bool, String> {
+        let inserted: Option<String> = redis::cmd("SET")
+            .arg(jti_key(jti))
+            .arg(1)
+            .arg("NX")
+            .arg("EX")
+            .arg(ttl(&expires_at))
+            .query_async(&mut self.connection.clone())
+            .await
+            .map_err(redis_error)?;
+        Ok(inserted.is_some())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+    use uuid::Uuid;
+
+    // needs a local redis-server
+    const REDIS_URL: &str = "redis://127.0.0.1/";
+
+    fn grant(expires_at: DateTime<Utc>) -> Grant {
+        Grant {
+            client_id: "foo".into(),
+            redirect_uri: "https://example.com/".into(),
+            access_token: "access".into(),
+            code_challenge: None,
+            code_challenge_method: None,
+            expires_at,
+            redeemed: false,
+        }
+    }
+
+    #[ignore]
+    #[tokio::test]
+    async fn test_codes() {
+        let storage = RedisStorage::connect(REDIS_URL).await.unwrap();
+        let code = Uuid::new_v4().to_string();
+        let grant = grant(Utc::now() + Duration::minutes(1));
+        storage.insert_code(&code, grant.clone()).await.unwrap();
+        assert_eq!(storage.get_code(&code).await.unwrap(), Some(grant));
+        assert!(storage.redeem_code(&code).await.unwrap());
+        assert!(!storage.redeem_code(&code).await.unwrap());
+        assert!(storage.get_code(&code).await.unwrap().unwrap().redeemed);
+        storage.remove_code(&code).await.unwrap();
+        assert_eq!(storage.get_code(&code).await.unwrap(), None);
+        assert!(!storage.redeem_code(&code).await.unwrap());
+    }
+
+    #[ignore]
+    #[tokio::test]
+    async fn test_expiry() {
+        let storage = RedisStorage::connect(REDIS_URL).await.unwrap();
+        let code = Uuid::new_v4().to_string();
+        storage
+            .insert_code(&code, grant(Utc::now() + Duration::seconds(1)))
+            .await
+            .unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(2100)).await;
+        assert_eq!(storage.get_code(&code).await.unwrap(), None);
+    }
+
+    #[ignore]
+    #[tokio::test]
+    async fn test_nonces() {
+        let storage = RedisStorage::connect(REDIS_URL).await.unwrap();
+        let nonce = Uuid::new_v4().to_string();
+        let expires_at = Utc.timestamp(Utc::now().timestamp() + 60, 0);
+        storage.insert_nonce(&nonce, expires_at).await.unwrap();
+        assert_eq!(
+            storage.remove_nonce(&nonce).await.unwrap(),
+            Some(expires_at)
+        );
+        assert_eq!(storage.remove_nonce(&nonce).await.unwrap(), None);
+    }
+
+    #[ignore]
+    #[tokio::test]
+    async fn test_jtis() {
+        let storage = RedisStorage::connect(REDIS_URL).await.unwrap();
+        let jti = Uuid::new_v4().to_string();
+        let expires_at = Utc::now() + Duration::minutes(1);
+        assert!(storage.insert_jti(&jti, expires_at).await.unwrap());
+        assert!(!storage.insert_jti(&jti, expires_at).await.unwrap());
+    }
+}
@@ -0,0 +1,367 @@
+use chrono::{DateTime, Duration, Utc};
+use openidconnect::core::{CoreErrorResponseType, CoreGenderClaim};
+use openidconnect::{AccessToken, RefreshToken, StandardClaims};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::access_token::issue_access_token;
+use crate::claims::Claims;
+use crate::config::{get_client, Config};
+use crate::keys::Keys;
+use crate::policy::{verify_access, Access};
+use crate::storage::Store;
+use crate::token::{
+    access_ttl, revoke, storage_error, store_token, token, token_error, AccessGrant,
+    NftTokenResponse, TokenError,
+};
+use crate::web3::OwnershipCondition;
+
+/// A refresh token, bound to the client and the ownership condition it was issued for.
+///
+/// It keeps the claims of the login, as it outlives the access token.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RefreshGrant {
+    pub client_id: String,
+    pub realm: String,
+    pub chain: String,
+    pub access_token: String,
+    pub condition: OwnershipCondition,
+    pub standard_claims: StandardClaims<CoreGenderClaim>,
+    pub additional_claims: Claims,
+    pub expires_at: DateTime<Utc>,
+}
+
+pub async fn issue_refresh_token(
+    config: &Config,
+    store: &Store,
+    access_token: &AccessToken,
+    access_grant: &AccessGrant,
+    standard_claims: &StandardClaims<CoreGenderClaim>,
+    additional_claims: &Claims,
+) -> Result<RefreshToken, String> {
+    let refresh_token = RefreshToken::new(Uuid::new_v4().to_string());
+    store
+        .insert_refresh_token(
+            refresh_token.secret(),
+            RefreshGrant {
+                client_id: access_grant.client_id.clone(),
+                realm: access_grant.realm.clone(),
+                chain: access_grant.chain.clone(),
+                access_token: access_token.secret().clone(),
+                condition: access_grant.condition.clone(),
+                standard_claims: standard_claims.clone(),
+                additional_claims: additional_claims.clone(),
+                expires_at: Utc::now() + Duration::seconds(config.refresh_ttl.unwrap_or(2_592_000)),
+            },
+        )
+        .await?;
+    Ok(refresh_token)
+}
+
+/// Issues new tokens for a refresh token, as long as the account still holds the NFT.
+///
+/// Every refresh token is used once and replaced by a new one. Tokens of an account that is no
+/// longer owner are revoked, while a failed ownership check keeps the refresh token valid.
+pub async fn refresh_token_grant(
+    config: &Config,
+    keys: &Keys,
+    store: &Store,
+    client_id: &str,
+    refresh_token: &str,
+) -> Result<NftTokenResponse, TokenError> {
+    let grant = take_refresh_grant(store, client_id, refresh_token).await?;
+    let client = get_client(config, client_id)
+        .ok_or_else(|| token_error(CoreErrorResponseType::InvalidClient, "unknown client"))?;
+    let access = verify_access(
+        config,
+        client,
+        &grant.condition,
+        &grant.chain,
+        &grant.additional_claims.account,
+        &grant.additional_claims.node,
+    )
+    .await;
+    refresh_with_access(config, keys, store, refresh_token, grant, access).await
+}
+
+/// Removes the refresh token of the client from the store, returning its grant.
+async fn take_refresh_grant(
+    store: &Store,
+    client_id: &str,
+    refresh_token: &str,
+) -> Result<RefreshGrant, TokenError> {
+    match store
+        .get_refresh_token(refresh_token)
+        .await
+        .map_err(storage_error)?
+    {
+        Some(grant) if grant.client_id != client_id => Err(token_error(
+            CoreErrorResponseType::InvalidGrant,
+            "refresh token was issued to another client",
+        )),
+        Some(grant) if grant.expires_at <= Utc::now() => {
+            store
+                .remove_refresh_token(refresh_token)
+                .await
+                .map_err(storage_error)?;
+            Err(token_error(
+                CoreErrorResponseType::InvalidGrant,
+                "refresh token expired",
+            ))
+        }
+        Some(_) => store
+            .remove_refresh_token(refresh_token)
+            .await
+            .map_err(storage_error)?
+            // none when redeemed by a concurrent request
+            .ok_or_else(|| {
+                token_error(CoreErrorResponseType::InvalidGrant, "invalid refresh token")
+            }),
+        None => Err(token_error(
+            CoreErrorResponseType::InvalidGrant,
+            "invalid refresh token",
+        )),
+    }
+}
+
+/// Issues the new tokens of a redeemed refresh token by the result of the ownership check.
+async fn refresh_with_access(
+    config: &Config,
+    keys: &Keys,
+    store: &Store,
+    refresh_token: &str,
+    grant: RefreshGrant,
+    access: Result<Access, String>,
+) -> Result<NftTokenResponse, TokenError> {
+    let client_id = grant.client_id.as_str();
+    let standard_claims = grant.standard_claims.clone();
+    let mut additional_claims = grant.additional_claims.clone();
+
+    let access = match access {
+        Ok(access) if access.is_owner => access,
+        Ok(_) => {
+            revoke(store, &grant.access_token)
+                .await
+                .map_err(storage_error)?;
+            return Err(token_error(
+                CoreErrorResponseType::InvalidGrant,
+                "account is no owner",
+            ));
+        }
+        Err(_) => {
+            store
+                .insert_refresh_token(refresh_token, grant)
+                .await
+                .map_err(storage_error)?;
+            return Err(token_error(
+                CoreErrorResponseType::Extension("temporarily_unavailable".to_string()),
+                "ownership could not be verified",
+            ));
+        }
+    };
+    additional_claims.balance = access.balance;
+    additional_claims.satisfied = access.satisfied;
+
+    revoke(store, &grant.access_token)
+        .await
+        .map_err(storage_error)?;
+
+    let access_grant = AccessGrant {
+        client_id: client_id.to_string(),
+        realm: grant.realm.clone(),
+        chain: grant.chain.clone(),
+        condition: grant.condition.clone(),
+        expires_at: Utc::now() + Duration::seconds(access_ttl(config)),
+    };
+    let access_token = issue_access_token(config, keys, &access_grant, &additional_claims);
+    let mut token = token(
+        config,
+        keys,
+        grant.realm.clone(),
+        client_id.to_string(),
+        None,
+        standard_claims.clone(),
+        additional_claims.clone(),
+        access_token.clone(),
+        None,
+    )
+    .await;
+    token.set_refresh_token(Some(
+        issue_refresh_token(
+            config,
+            store,
+            &access_token,
+            &access_grant,
+            &standard_claims,
+            &additional_claims,
+        )
+        .await
+        .map_err(storage_error)?,
+    ));
+
+    store_token(
+        store,
+        access_grant,
+        token.clone(),
+        standard_claims,
+        additional_claims,
+    )
+    .await
+    .map_err(storage_error)?;
+
+    Ok(token)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rocket;
+    use crate::tests::issue_code;
+    use futures::executor::block_on;
+    use openidconnect::OAuth2TokenResponse;
+    use rocket::http::{ContentType, Status};
+    use rocket::local::blocking::{Client, LocalResponse};
+    use serde_json::Value;
+
+    fn redeem(client: &Client, client_id: &str) -> (String, String) {
+        let code = issue_code(client, client_id, "https://example.com");
+        let response = client
+            .post("/okt/token")
+            .header(ContentType::Form)
+            .body(format!(
+                "grant_type=authorization_code&code={}&redirect_uri=https://example.com&client_id={}",
+                code, client_id
+            ))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let token = response.into_json::<Value>().unwrap();
+        let secret = |name: &str| token.get(name).unwrap().as_str().unwrap().to_string();
+        (secret("access_token"), secret("refresh_token"))
+    }
+
+    fn refresh<'c>(client: &'c Client, client_id: &str, refresh_token: &str) -> LocalResponse<'c> {
+        client
+            .post("/okt/token")
+            .header(ContentType::Form)
+            .body(format!(
+                "grant_type=refresh_token&refresh_token={}&client_id={}",
+                refresh_token, client_id
+            ))
+            .dispatch()
+    }
+
+    fn refresh_with(
+        client: &Client,
+        refresh_token: &str,
+        access: Result<Access, String>,
+    ) -> Result<NftTokenResponse, TokenError> {
+        let rocket = client.rocket();
+        let config = rocket.state::<Config>().unwrap();
+        let keys = rocket.state::<Keys>().unwrap();
+        let store = rocket.state::<Store>().unwrap();
+        block_on(async {
+            let grant = take_refresh_grant(store, "foo", refresh_token).await?;
+            refresh_with_access(config, keys, store, refresh_token, grant, access).await
+        })
+    }
+
+    fn access(is_owner: bool) -> Result<Access, String> {
+        Ok(Access {
+            is_owner,
+            balance: "1".to_string(),
+            satisfied: Vec::new(),
+        })
+    }
+
+    #[test]
+    fn refresh_token_of_other_client() {
+        let client = Client::tracked(rocket()).expect("valid rocket instance");
+        let (_, refresh_token) = redeem(&client, "foo");
+
+        let response = refresh(&client, "bar", &refresh_token);
+        assert_eq!(response.status(), Status::BadRequest);
+        let response = refresh(&client, "foo", "invalid");
+        assert_eq!(response.status(), Status::BadRequest);
+    }
+
+    #[test]
+    fn refresh_token_kept_without_ownership_check() {
+        let client = Client::tracked(rocket()).expect("valid rocket instance");
+        let (_, refresh_token) = redeem(&client, "foo");
+
+        // the test claims have no node, so the ownership can not be checked
+        let response = refresh(&client, "foo", &refresh_token);
+        assert_eq!(response.status(), Status::ServiceUnavailable);
+        let error = response.into_json::<Value>().unwrap();
+        assert_eq!(error.get("error").unwrap(), "temporarily_unavailable");
+
+        let store = client.rocket().state::<Store>().unwrap();
+        assert!(block_on(store.get_refresh_token(&refresh_token))
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn refresh_of_owner() {
+        let client = Client::tracked(rocket()).expect("valid rocket instance");
+        let (access_token, refresh_token) = redeem(&client, "foo");
+
+        let token = refresh_with(&client, &refresh_token, access(true)).unwrap();
+        let new_access_token = token.access_token().secret();
+        let new_refresh_token = token.refresh_token().unwrap().secret();
+        assert_ne!(new_access_token, &access_token);
+        assert_ne!(new_refresh_token, &refresh_token);
+
+        let store = client.rocket().state::<Store>().unwrap();
+        assert!(block_on(store.get_session(&access_token))
+            .unwrap()
+            .is_none());
+        assert!(block_on(store.get_refresh_token(&refresh_token))
+            .unwrap()
+            .is_none());
+        let session = block_on(store.get_session(new_access_token))
+            .unwrap()
+            .unwrap();
+        assert_eq!(session.additional_claims.balance, "1");
+        assert!(block_on(store.get_refresh_token(new_refresh_token))
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn refresh_of_former_owner() {
+        let client = Client::tracked(rocket()).expect("valid rocket instance");
+        let (access_token, refresh_token) = redeem(&client, "foo");
+
+        let (status, error) = refresh_with(&client, &refresh_token, access(false)).unwrap_err();
+        assert_eq!(status, Status::BadRequest);
+        assert_eq!(error.error(), &CoreErrorResponseType::InvalidGrant);
+
+        let store = client.rocket().state::<Store>().unwrap();
+        assert!(block_on(store.get_session(&access_token))
+            .unwrap()
+            .is_none());
+        assert!(block_on(store.get_refresh_token(&refresh_token))
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn refresh_with_node_error() {
+        let client = Client::tracked(rocket()).expect("valid rocket instance");
+        let (access_token, refresh_token) = redeem(&client, "foo");
+
+        let (status, _) =
+            refresh_with(&client, &refresh_token, Err("node unreachable".to_string())).unwrap_err();
+        assert_eq!(status, Status::ServiceUnavailable);
+
+        let store = client.rocket().state::<Store>().unwrap();
+        assert!(block_on(store.get_session(&access_token))
+            .unwrap()
+            .is_some());
+        assert!(block_on(store.get_refresh_token(&refresh_token))
+            .unwrap()
+            .is_some());
+        assert!(refresh_with(&client, &refresh_token, access(true)).is_ok());
+    }
+}
@@ -0,0 +1,213 @@
+use openidconnect::core::CoreErrorResponseType;
+use rocket::form::Form;
+use rocket::State;
+
+use crate::client_auth::{authenticate_client, BasicAuth, ClientCredentials};
+use crate::config::Config;
+use crate::storage::Store;
+use crate::token::{revoke, storage_error, token_error, TokenError};
+
+#[derive(FromForm)]
+pub struct RevokeData {
+    pub token: Option<String>,
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+    pub client_assertion_type: Option<String>,
+    pub client_assertion: Option<String>,
+}
+
+/// Revokes a refresh or access token of the client, together with the tokens issued with it.
+///
+/// Unknown tokens are ignored as required by RFC 7009 section 2.2. Both token types are looked
+/// up, so the optional `token_type_hint` is not needed.
+pub async fn revoke_token(store: &Store, client_id: &str, token: &str) -> Result<(), TokenError> {
+    let other_client = || {
+        token_error(
+            CoreErrorResponseType::InvalidGrant,
+            "token was issued to another client",
+        )
+    };
+
+    if let Some(grant) = store
+        .get_refresh_token(token)
+        .await
+        .map_err(storage_error)?
+    {
+        if grant.client_id != client_id {
+            return Err(other_client());
+        }
+        store
+            .remove_refresh_token(token)
+            .await
+            .map_err(storage_error)?;
+        return revoke(store, &grant.access_token)
+            .await
+            .map_err(storage_error);
+    }
+
+    if let Some(session) = store.get_session(token).await.map_err(storage_error)? {
+        if session.grant.client_id != client_id {
+            return Err(other_client());
+        }
+        revoke(store, token).await.map_err(storage_error)?;
+    }
+    Ok(())
+}
+
+#[post("/revoke", data = "<revoke_data>")]
+pub async fn default_revoke_endpoint(
+    config: &State<Config>,
+    store: &State<Store>,
+    basic_auth: BasicAuth,
+    revoke_data: Form<RevokeData>,
+) -> Result<(), TokenError> {
+    revoke_endpoint(config, store, basic_auth, "default".into(), revoke_data).await
+}
+
+#[post("/<realm>/revoke", data = "<revoke_data>")]
+pub async fn revoke_endpoint(
+    config: &State<Config>,
+    store: &State<Store>,
+    basic_auth: BasicAuth,
+    realm: String,
+    revoke_data: Form<RevokeData>,
+) -> Result<(), TokenError> {
+    let revoke_data = revoke_data.into_inner();
+
+    let credentials = ClientCredentials {
+        basic: basic_auth.0,
+        client_id: revoke_data.client_id,
+        client_secret: revoke_data.client_secret,
+        client_assertion_type: revoke_data.client_assertion_type,
+        client_assertion: revoke_data.client_assertion,
+    };
+    let client_id = authenticate_client(config, store, &realm, &credentials)
+        .await
+        .map_err(|err| token_error(CoreErrorResponseType::InvalidClient, &err))?;
+
+    let token = revoke_data
+        .token
+        .ok_or_else(|| token_error(CoreErrorResponseType::InvalidRequest, "token missing"))?;
+    revoke_token(store, &client_id, &token).await
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::rocket;
+    use crate::storage::Store;
+    use crate::tests::issue_code;
+    use futures::executor::block_on;
+    use rocket::http::{ContentType, Header, Status};
+    use rocket::local::blocking::Client;
+    use serde_json::Value;
+
+    fn basic_auth(client_id: &str, client_secret: &str) -> Header<'static> {
+        Header::new(
+            "Authorization",
+            format!(
+                "Basic {}",
+                base64::encode(format!("{}:{}", client_id, client_secret))
+            ),
+        )
+    }
+
+    fn redeem(client: &Client, client_id: &str, client_secret: Option<&str>) -> Value {
+        let code = issue_code(client, client_id, "https://example.com");
+        let mut request = client.post("/okt/token").header(ContentType::Form);
+        if let Some(client_secret) = client_secret {
+            request = request.header(basic_auth(client_id, client_secret));
+        }
+        let response = request
+            .body(format!(
+                "grant_type=authorization_code&code={}&redirect_uri=https://example.com&client_id={}",
+                code, client_id
+            ))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        response.into_json::<Value>().unwrap()
+    }
+
+    fn field(token: &Value, name: &str) -> String {
+        token.get(name).unwrap().as_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn revoke_access_token() {
+        let client = Client::tracked(rocket()).expect("valid rocket instance");
+        let token = redeem(&client, "foo", None);
+        let access_token = field(&token, "access_token");
+
+        let response = client
+            .post("/okt/revoke")
+            .header(ContentType::Form)
+            .body(format!("token={}&client_id=bar", access_token))
+            .dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+
+        let response = client
+            .post("/okt/revoke")
+            .header(ContentType::Form)
+            .body(format!(
+                "token={}&token_type_hint=access_token&client_id=foo",
+                access_token
+            ))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+
+        let response = client
+            .get("/okt/userinfo")
+            .header(Header::new(
+                "Authorization",
+                format!("Bearer {}", access_token),
+            ))
+            .dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+        let store = client.rocket().state::<Store>().unwrap();
+        assert!(
+            block_on(store.get_refresh_token(&field(&token, "refresh_token")))
+                .unwrap()
+                .is_none()
+        );
+
+        // revoking an unknown token succeeds
+        let response = client
+            .post("/okt/revoke")
+            .header(ContentType::Form)
+            .body(format!("token={}&client_id=foo", access_token))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+    }
+
+    #[test]
+    fn revoke_refresh_token() {
+        let client = Client::tracked(rocket()).expect("valid rocket instance");
+        let token = redeem(&client, "confidential", Some("secret"));
+        let refresh_token = field(&token, "refresh_token");
+
+        let response = client
+            .post("/okt/revoke")
+            .header(ContentType::Form)
+            .body(format!("token={}&client_id=confidential", refresh_token))
+            .dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+
+        let response = client
+            .post("/okt/revoke")
+            .header(ContentType::Form)
+            .header(basic_auth("confidential", "secret"))
+            .body(format!(
+                "token={}&token_type_hint=refresh_token",
+                refresh_token
+            ))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+
+        let store = client.rocket().state::<Store>().unwrap();
+        assert!(block_on(store.get_refresh_token(&refresh_token))
+            .unwrap()
+            .is_none());
+        assert!(block_on(store.get_session(&field(&token, "access_token")))
+            .unwrap()
+            .is_none());
+    }
+}
@@ -0,0 +1,364 @@
+use chrono::{DateTime, Duration, Utc};
+use std::str::FromStr;
+use url::Url;
+
+use crate::web3::{eth_message, validate_signature};
+
+const PREAMBLE: &str = " wants you to sign in with your Ethereum account:";
+const MAX_AGE_SECONDS: i64 = 600;
+const CLOCK_SKEW_SECONDS: i64 = 60;
+
+/// A Sign-In with Ethereum message as specified in EIP-4361.
+#[derive(Debug, PartialEq, Clone)]
+pub struct SiweMessage {
+    pub domain: String,
+    pub address: String,
+    pub statement: Option<String>,
+    pub uri: String,
+    pub version: String,
+    pub chain_id: i32,
+    pub nonce: String,
+    pub issued_at: DateTime<Utc>,
+    pub expiration_time: Option<DateTime<Utc>>,
+    pub not_before: Option<DateTime<Utc>>,
+    pub request_id: Option<String>,
+    pub resources: Vec<String>,
+}
+
+fn parse_time(value: &str) -> Result<DateTime<Utc>, String> {
+    DateTime::parse_from_rfc3339(value)
+        .map(|time| time.with_timezone(&Utc))
+        .map_err(|_| format!("invalid timestamp {}", value))
+}
+
+fn field<'a>(line: Option<&'a str>, tag: &str) -> Result<&'a str, String> {
+    line.and_then(|line| line.strip_prefix(tag))
+        .and_then(|line| line.strip_prefix(": "))
+        .ok_or(format!("{} missing", tag))
+}
+
+impl FromStr for SiweMessage {
+    type Err = String;
+
+    fn from_str(message: &str) -> Result<Self, Self::Err> {
+        let mut lines = message.lines().peekable();
+
+        let domain = lines
+            .next()
+            .and_then(|line| line.strip_suffix(PREAMBLE))
+            .ok_or("preamble missing")?;
+        let domain = domain.split("://").last().unwrap_or(domain).to_string();
+
+        let address = lines.next().ok_or("address missing")?.to_string();
+        if !address.starts_with("0x") || address.len() != 42 {
+            return Err("invalid address".to_string());
+        }
+
+        let mut statement = None;
+        while let Some(line) = lines.peek() {
+            if line.starts_with("URI: ") {
+                break;
+            }
+            if !line.is_empty() {
+                statement = Some(line.to_string());
+            }
+            lines.next();
+        }
+
+        let uri = field(lines.next(), "URI")?.to_string();
+        let version = field(lines.next(), "Version")?.to_string();
+        if version != "1" {
+            return Err("unsupported version".to_string());
+        }
+        let chain_id = field(lines.next(), "Chain ID")?
+            .parse::<i32>()
+            .map_err(|_| "invalid chain id")?;
+        let nonce = field(lines.next(), "Nonce")?.to_string();
+        let issued_at = parse_time(field(lines.next(), "Issued At")?)?;
+
+        let mut expiration_time = None;
+        let mut not_before = None;
+        let mut request_id = None;
+        let mut resources = Vec::new();
+
+        if let Some(Ok(value)) = lines
+            .peek()
+            .map(|line| field(Some(line), "Expiration Time"))
+        {
+            expiration_time = Some(parse_time(value)?);
+            lines.next();
+        }
+        if let Some(Ok(value)) = lines.peek().map(|line| field(Some(line), "Not Before")) {
+            not_before = Some(parse_time(value)?);
+            lines.next();
+        }
+        if let Some(Ok(value)) = lines.peek().map(|line| field(Some(line), "Request ID")) {
+            request_id = Some(value.to_string());
+            lines.next();
+        }
+        if lines.peek() == Some(&"Resources:") {
+            lines.next();
+            for line in lines.by_ref() {
+                match line.strip_prefix("- ") {
+                    Some(resource) => resources.push(resource.to_string()),
+                    None => return Err("invalid resource".to_string()),
+                }
+            }
+        }
+        if lines.next().is_some() {
+            return Err("unexpected trailing content".to_string());
+        }
+
+        Ok(SiweMessage {
+            domain,
+            address,
+            statement,
+            uri,
+            version,
+            chain_id,
+            nonce,
+            issued_at,
+            expiration_time,
+            not_before,
+            request_id,
+            resources,
+        })
+    }
+}
+
+impl SiweMessage {
+    /// Checks the message fields against the values expected by this provider.
+    ///
+    /// The `URI` has to be of the same origin as `origin`, the `ext_hostname` of this provider.
+    pub fn verify(
+        &self,
+        domain: &str,
+        origin: &str,
+        account: &str,
+        chain_id: i32,
+        nonce: &str,
+        now: DateTime<Utc>,
+    ) -> Result<(), String> {
+        if self.domain != domain {
+            return Err("domain mismatch".to_string());
+        }
+        let uri = Url::parse(&self.uri).map_err(|_| "invalid uri".to_string())?;
+        if Url::parse(origin).map(|origin| origin.origin()) != Ok(uri.origin()) {
+            return Err("uri mismatch".to_string());
+        }
+        if self.address.to_lowercase() != account.to_lowercase() {
+            return Err("address mismatch".to_string());
+        }
+        if self.chain_id != chain_id {
+            return Err("chain id mismatch".to_string());
+        }
+        if self.nonce != nonce {
+            return Err("nonce mismatch".to_string());
+        }
+        if self.issued_at > now + Duration::seconds(CLOCK_SKEW_SECONDS) {
+            return Err("message issued in the future".to_string());
+        }
+        let expiration_time = self
+            .expiration_time
+            .unwrap_or(self.issued_at + Duration::seconds(MAX_AGE_SECONDS));
+        if expiration_time <= now {
+            return Err("message expired".to_string());
+        }
+        if let Some(not_before) = self.not_before {
+            if not_before > now + Duration::seconds(CLOCK_SKEW_SECONDS) {
+                return Err("message not yet valid".to_string());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parses an EIP-4361 message, checks its fields and verifies that the account signed it.
+#[allow(clippy::too_many_arguments)]
+pub async fn validate_siwe_message(
+    message: &str,
+    signature: &str,
+    domain: &str,
+    origin: &str,
+    account: &str,
+    chain_id: i32,
+    nonce: &str,
+    node_provider: &str,
+) -> Result<SiweMessage, String> {
+    let siwe_message = SiweMessage::from_str(message)?;
+    siwe_message.verify(domain, origin, account, chain_id, nonce, Utc::now())?;
+    if !validate_signature(
+        account.to_string(),
+        eth_message(message.to_string()),
+        signature.to_string(),
+        node_provider.to_string(),
+    )
+    .await
+    {
+        return Err("no valide signature".to_string());
+    }
+    Ok(siwe_message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::wallet;
+
+    fn message(address: &str, issued_at: DateTime<Utc>) -> String {
+        format!(
+            "localhost:8000 wants you to sign in with your Ethereum account:\n\
+            {}\n\
+            \n\
+            Log in with your NFT Account\n\
+            \n\
+            URI: http://localhost:8000\n\
+            Version: 1\n\
+            Chain ID: 65\n\
+            Nonce: dotzxrenodo\n\
+            Issued At: {}\n\
+            Expiration Time: {}",
+            address,
+            issued_at.to_rfc3339(),
+            (issued_at + Duration::minutes(5)).to_rfc3339()
+        )
+    }
+
+    #[test]
+    fn test_parse() {
+        let now = Utc::now();
+        let address = "0x9c9E8eAbD947658bDb713E0d3eBfe56860abdb8D";
+        let siwe_message = SiweMessage::from_str(&message(address, now)).unwrap();
+        assert_eq!(siwe_message.domain, "localhost:8000");
+        assert_eq!(siwe_message.address, address);
+        assert_eq!(
+            siwe_message.statement,
+            Some("Log in with your NFT Account".to_string())
+        );
+        assert_eq!(siwe_message.uri, "http://localhost:8000");
+        assert_eq!(siwe_message.chain_id, 65);
+        assert_eq!(siwe_message.nonce, "dotzxrenodo");
+        assert!(siwe_message.expiration_time.is_some());
+        assert!(siwe_message.resources.is_empty());
+
+        assert!(SiweMessage::from_str("").is_err());
+        assert!(SiweMessage::from_str(&message(address, now).replace("Version: 1", "")).is_err());
+    }
+
+    #[test]
+    fn test_verify() {
+        let now = Utc::now();
+        let address = "0x9c9E8eAbD947658bDb713E0d3eBfe56860abdb8D";
+        let account = address.to_lowercase();
+        let siwe_message = SiweMessage::from_str(&message(address, now)).unwrap();
+        assert!(siwe_message
+            .verify(
+                "localhost:8000",
+                "http://localhost:8000",
+                &account,
+                65,
+                "dotzxrenodo",
+                now
+            )
+            .is_ok());
+        assert!(siwe_message
+            .verify(
+                "example.com",
+                "http://localhost:8000",
+                &account,
+                65,
+                "dotzxrenodo",
+                now
+            )
+            .is_err());
+        assert!(siwe_message
+            .verify(
+                "localhost:8000",
+                "http://localhost:8000",
+                &account,
+                42,
+                "dotzxrenodo",
+                now
+            )
+            .is_err());
+        assert!(siwe_message
+            .verify(
+                "localhost:8000",
+                "http://localhost:8000",
+                &account,
+                65,
+                "other",
+                now
+            )
+            .is_err());
+        assert!(siwe_message
+            .verify(
+                "localhost:8000",
+                "http://localhost:8000",
+                &account,
+                65,
+                "dotzxrenodo",
+                now + Duration::minutes(10)
+            )
+            .is_err());
+        for origin in [
+            "https://localhost:8000",
+            "http://localhost",
+            "http://evil.example.com",
+        ] {
+            assert_eq!(
+                siwe_message.verify("localhost:8000", origin, &account, 65, "dotzxrenodo", now),
+                Err("uri mismatch".to_string())
+            );
+        }
+        let other_uri = SiweMessage::from_str(
+            &message(address, now)
+                .replace("URI: http://localhost:8000", "URI: http://evil.example.com"),
+        )
+        .unwrap();
+        assert_eq!(
+            other_uri.verify(
+                "localhost:8000",
+                "http://localhost:8000",
+                &account,
+                65,
+                "dotzxrenodo",
+                now
+            ),
+            Err("uri mismatch".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_validate_siwe_message() {
+        let key = wallet::key();
+        let account = wallet::address(&key);
+        let message = message(&account, Utc::now());
+        let signature = wallet::sign(&key, &message);
+        let node_provider = "http://localhost:8545";
+        assert!(validate_siwe_message(
+            &message,
+            &signature,
+            "localhost:8000",
+            "http://localhost:8000",
+            &account,
+            65,
+            "dotzxrenodo",
+            node_provider
+        )
+        .await
+        .is_ok());
+        assert!(validate_siwe_message(
+            &message,
+            &signature,
+            "localhost:8000",
+            "http://localhost:8000",
+            "0x9c9e8eabd947658bdb713e0d3ebfe56860abdb8d",
+            65,
+            "dotzxrenodo",
+            node_provider
+        )
+        .await
+        .is_err());
+    }
+}
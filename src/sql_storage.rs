@@ -0,0 +1,368 @@
+use chrono::{DateTime, TimeZone, Utc};
+use sqlx::any::{AnyPool, AnyPoolOptions};
+use sqlx::Row;
+
+use crate::refresh::RefreshGrant;
+use crate::storage::{Session, Storage};
+use crate::token::Grant;
+
+/// Keeps the state in SQLite or PostgreSQL, selected by the scheme of the url.
+///
+/// Expired rows of a table are purged whenever a row is inserted into it.
+pub struct SqlStorage {
+    pool: AnyPool,
+}
+
+fn sql_error(error: sqlx::Error) -> String {
+    format!("storage error: {}", error)
+}
+
+fn to_json<T: serde::Serialize>(value: &T) -> Result<String, String> {
+    serde_json::to_string(value).map_err(|err| format!("storage error: {}", err))
+}
+
+fn from_json<T: serde::de::DeserializeOwned>(value: &str) -> Result<T, String> {
+    serde_json::from_str(value).map_err(|err| format!("storage error: {}", err))
+}
+
+impl SqlStorage {
+    /// Connects to the database and runs the migrations.
+    pub async fn connect(url: &str) -> Result<Self, String> {
+        // every connection would open its own in-memory database
+        let max_connections = if url.contains(":memory:") { 1 } else { 10 };
+        let pool = AnyPoolOptions::new()
+            .max_connections(max_connections)
+            .connect(url)
+            .await
+            .map_err(sql_error)?;
+        sqlx::migrate!()
+            .run(&pool)
+            .await
+            .map_err(|err| format!("storage migration failed: {}", err))?;
+        Ok(SqlStorage { pool })
+    }
+
+    async fn purge(&self, table: &str) -> Result<u64, String> {
+        let result = sqlx::query(&format!("DELETE FROM {} WHERE expires_at < $1", table))
+            .bind(Utc::now().timestamp())
+            .execute(&self.pool)
+            .await
+            .map_err(sql_error)?;
+        Ok(result.rows_affected())
+    }
+}
+
+#[rocket::async_trait]
+impl Storage for SqlStorage {
+    async fn insert_code(&self, code: &str, grant: Grant) -> Result<(), String> {
+        self.purge("codes").await?;
+        sqlx::query(
+            "INSERT INTO codes (code, grant_data, redeemed, expires_at) VALUES ($1, $2, $3, $4)
+             ON CONFLICT (code) DO UPDATE SET grant_data = $2, redeemed = $3, expires_at = $4",
+        )
+        .bind(code)
+        .bind(to_json(&grant)?)
+        .bind(grant.redeemed as i32)
+        .bind(grant.expires_at.timestamp())
+        .execute(&self.pool)
+        .await
+        .map_err(sql_error)?;
+        Ok(())
+    }
+
+    async fn get_code(&self, code: &str) -> Result<Option<Grant>, String> {
+        let row = sqlx::query("SELECT grant_data, redeemed FROM codes WHERE code = $1")
+            .bind(code)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(sql_error)?;
+        match row {
+            Some(row) => {
+                let mut grant: Grant = from_json(&row.try_get::<String, _>(0).map_err(sql_error)?)?;
+                grant.redeemed = row.try_get::<i32, _>(1).map_err(sql_error)? != 0;
+                Ok(Some(grant))
+            }
+            None => Ok(None),
+        }
+    }
+
+    async fn redeem_code(&self, code: &str) -> Result<bool, String> {
+        let result = sqlx::query("UPDATE codes SET redeemed = 1 WHERE code = $1 AND redeemed = 0")
+            .bind(code)
+            .execute(&self.pool)
+            .await
+            .map_err(sql_error)?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    async fn remove_code(&self, code: &str) -> Result<(), String> {
+        sqlx::query("DELETE FROM codes WHERE code = $1")
+            .bind(code)
+            .execute(&self.pool)
+            .await
+            .map_err(sql_error)?;
+        Ok(())
+    }
+
+    async fn insert_session(&self, access_token: &str, session: Session) -> Result<(), String> {
+        self.purge("sessions").await?;
+        sqlx::query(
+            "INSERT INTO sessions (access_token, session_data, expires_at) VALUES ($1, $2, $3)
+             ON CONFLICT (access_token) DO UPDATE SET session_data = $2, expires_at = $3",
+        )
+        .bind(access_token)
+        .bind(to_json(&session)?)
+        .bind(session.grant.expires_at.timestamp())
+        .execute(&self.pool)
+        .await
+        .map_err(sql_error)?;
+        Ok(())
+    }
+
+    async fn get_session(&self, access_token: &str) -> Result<Option<Session>, String> {
+        let row = sqlx::query("SELECT session_data FROM sessions WHERE access_token = $1")
+            .bind(access_token)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(sql_error)?;
+        match row {
+            Some(row) => from_json(&row.try_get::<String, _>(0).map_err(sql_error)?).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    async fn remove_session(&self, access_token: &str) -> Result<Option<Session>, String> {
+        let row =
+            sqlx::query("DELETE FROM sessions WHERE access_token = $1 RETURNING session_data")
+                .bind(access_token)
+                .fetch_optional(&self.pool)
+                .await
+                .map_err(sql_error)?;
+        match row {
+            Some(row) => from_json(&row.try_get::<String, _>(0).map_err(sql_error)?).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    async fn insert_refresh_token(
+        &self,
+        refresh_token: &str,
+        grant: RefreshGrant,
+    ) -> Result<(), String> {
+        self.purge("refresh_tokens").await?;
+        sqlx::query(
+            "INSERT INTO refresh_tokens (refresh_token, grant_data, expires_at) VALUES ($1, $2, $3)
+             ON CONFLICT (refresh_token) DO UPDATE SET grant_data = $2, expires_at = $3",
+        )
+        .bind(refresh_token)
+        .bind(to_json(&grant)?)
+        .bind(grant.expires_at.timestamp())
+        .execute(&self.pool)
+        .await
+        .map_err(sql_error)?;
+        Ok(())
+    }
+
+    async fn get_refresh_token(&self, refresh_token: &str) -> Result<Option<RefreshGrant>, String> {
+        let row = sqlx::query("SELECT grant_data FROM refresh_tokens WHERE refresh_token = $1")
+            .bind(refresh_token)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(sql_error)?;
+        match row {
+            Some(row) => from_json(&row.try_get::<String, _>(0).map_err(sql_error)?).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    async fn remove_refresh_token(
+        &self,
+        refresh_token: &str,
+    ) -> Result<Option<RefreshGrant>, String> {
+        let row =
+            sqlx::query("DELETE FROM refresh_tokens WHERE refresh_token = $1 RETURNING grant_data")
+                .bind(refresh_token)
+                .fetch_optional(&self.pool)
+                .await
+                .map_err(sql_error)?;
+        match row {
+            Some(row) => from_json(&row.try_get::<String, _>(0).map_err(sql_error)?).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    async fn insert_nonce(&self, nonce: &str, expires_at: DateTime<Utc>) -> Result<(), String> {
+        self.purge("nonces").await?;
+        sqlx::query(
+            "INSERT INTO nonces (nonce, expires_at) VALUES ($1, $2)
+             ON CONFLICT (nonce) DO UPDATE SET expires_at = $2",
+        )
+        .bind(nonce)
+        .bind(expires_at.timestamp())
+        .execute(&self.pool)
+        .await
+        .map_err(sql_error)?;
+        Ok(())
+    }
+
+    async fn remove_nonce(&self, nonce: &str) -> Result<Option<DateTime<Utc>>, String> {
+        let row = sqlx::query("DELETE FROM nonces WHERE nonce = $1 RETURNING expires_at")
+            .bind(nonce)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(sql_error)?;
+        match row {
+            Some(row) => Ok(Some(
+                Utc.timestamp(row.try_get::<i64, _>(0).map_err(sql_error)?, 0),
+            )),
+            None => Ok(None),
+        }
+    }
+
+    async fn insert_jti(&self, jti: &str, expires_at: DateTime<Utc>) -> Result<bool, String> {
+        self.purge("jtis").await?;
+        let result = sqlx::query(
+            "INSERT INTO jtis (jti, expires_at) VALUES ($1, $2) ON CONFLICT (jti) DO NOTHING",
+        )
+        .bind(jti)
+        .bind(expires_at.timestamp())
+        .execute(&self.pool)
+        .await
+        .map_err(sql_error)?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    async fn purge_expired(&self) -> Result<u64, String> {
+        let mut purged = 0;
+        for table in ["codes", "sessions", "refresh_tokens", "nonces", "jtis"] {
+            purged += self.purge(table).await?;
+        }
+        Ok(purged)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::Store;
+    use chrono::Duration;
+    use futures::executor::block_on;
+    use openidconnect::OAuth2TokenResponse;
+
+    fn grant(expires_at: DateTime<Utc>) -> Grant {
+        Grant {
+            client_id: "foo".into(),
+            redirect_uri: "https://example.com/".into(),
+            access_token: "access".into(),
+            code_challenge: None,
+            code_challenge_method: None,
+            expires_at,
+            redeemed: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_codes() {
+        let storage = SqlStorage::connect("sqlite::memory:").await.unwrap();
+        let grant = grant(Utc::now() + Duration::minutes(1));
+        storage.insert_code("code", grant.clone()).await.unwrap();
+        assert_eq!(storage.get_code("code").await.unwrap(), Some(grant));
+        assert!(storage.redeem_code("code").await.unwrap());
+        assert!(!storage.redeem_code("code").await.unwrap());
+        assert!(storage.get_code("code").await.unwrap().unwrap().redeemed);
+        storage.remove_code("code").await.unwrap();
+        assert_eq!(storage.get_code("code").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_purge_expired() {
+        let storage = SqlStorage::connect("sqlite::memory:").await.unwrap();
+        storage
+            .insert_code("expired", grant(Utc::now() - Duration::minutes(1)))
+            .await
+            .unwrap();
+        storage
+            .insert_code("valid", grant(Utc::now() + Duration::minutes(1)))
+            .await
+            .unwrap();
+        assert_eq!(storage.get_code("expired").await.unwrap(), None);
+        assert!(storage.get_code("valid").await.unwrap().is_some());
+
+        storage
+            .insert_nonce("expired", Utc::now() - Duration::seconds(1))
+            .await
+            .unwrap();
+        assert_eq!(storage.purge_expired().await.unwrap(), 1);
+        assert_eq!(storage.remove_nonce("expired").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_nonces() {
+        let storage = SqlStorage::connect("sqlite::memory:").await.unwrap();
+        let expires_at = Utc.timestamp(Utc::now().timestamp() + 60, 0);
+        storage.insert_nonce("nonce", expires_at).await.unwrap();
+        assert_eq!(
+            storage.remove_nonce("nonce").await.unwrap(),
+            Some(expires_at)
+        );
+        assert_eq!(storage.remove_nonce("nonce").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_jtis() {
+        let storage = SqlStorage::connect("sqlite::memory:").await.unwrap();
+        let expires_at = Utc::now() + Duration::minutes(1);
+        assert!(storage.insert_jti("jti", expires_at).await.unwrap());
+        assert!(!storage.insert_jti("jti", expires_at).await.unwrap());
+    }
+
+    #[test]
+    fn test_sessions_and_refresh_tokens() {
+        // issue a session and refresh token with the default storage and copy them to sqlite
+        let client = rocket::local::blocking::Client::tracked(crate::rocket()).unwrap();
+        let code = crate::tests::issue_code(&client, "foo", "https://example.com");
+        let store = client.rocket().state::<Store>().unwrap();
+        let grant = block_on(store.get_code(&code)).unwrap().unwrap();
+        let session = block_on(store.get_session(&grant.access_token))
+            .unwrap()
+            .unwrap();
+        let refresh_token = session.token.refresh_token().unwrap().secret().clone();
+        let refresh_grant = block_on(store.get_refresh_token(&refresh_token))
+            .unwrap()
+            .unwrap();
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let storage = SqlStorage::connect("sqlite::memory:").await.unwrap();
+            storage
+                .insert_session(&grant.access_token, session.clone())
+                .await
+                .unwrap();
+            let stored = storage.get_session(&grant.access_token).await.unwrap();
+            assert_eq!(stored.unwrap().additional_claims, session.additional_claims);
+            assert!(storage
+                .remove_session(&grant.access_token)
+                .await
+                .unwrap()
+                .is_some());
+            assert!(storage
+                .get_session(&grant.access_token)
+                .await
+                .unwrap()
+                .is_none());
+
+            storage
+                .insert_refresh_token(&refresh_token, refresh_grant.clone())
+                .await
+                .unwrap();
+            assert_eq!(
+                storage.get_refresh_token(&refresh_token).await.unwrap(),
+                Some(refresh_grant.clone())
+            );
+            assert_eq!(
+                storage.remove_refresh_token(&refresh_token).await.unwrap(),
+                Some(refresh_grant)
+            );
+        });
+    }
+}
@@ -0,0 +1,428 @@
+use chrono::{DateTime, Utc};
+use openidconnect::core::CoreGenderClaim;
+use openidconnect::StandardClaims;
+use rocket::fairing::AdHoc;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::claims::Claims;
+use crate::config::Config;
+#[cfg(feature = "redis")]
+use crate::redis_storage::RedisStorage;
+use crate::refresh::RefreshGrant;
+#[cfg(feature = "sql")]
+use crate::sql_storage::SqlStorage;
+use crate::token::{AccessGrant, Grant, NftTokenResponse};
+
+/// An issued token together with the claims it resolves to, keyed by its access token.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Session {
+    pub grant: AccessGrant,
+    pub token: NftTokenResponse,
+    pub standard_claims: StandardClaims<CoreGenderClaim>,
+    pub additional_claims: Claims,
+}
+
+/// Storage of codes, sessions, refresh tokens, nonces and the ids of used client assertions.
+///
+/// Every entry carries its expiry, so a backend may drop it once expired. Callers still check
+/// the expiry themselves, as backends are not required to.
+#[rocket::async_trait]
+pub trait Storage: Send + Sync {
+    async fn insert_code(&self, code: &str, grant: Grant) -> Result<(), String>;
+    async fn get_code(&self, code: &str) -> Result<Option<Grant>, String>;
+    /// Marks the code as redeemed, returning false if it was redeemed before.
+    async fn redeem_code(&self, code: &str) -> Result<bool, String>;
+    async fn remove_code(&self, code: &str) -> Result<(), String>;
+
+    async fn insert_session(&self, access_token: &str, session: Session) -> Result<(), String>;
+    async fn get_session(&self, access_token: &str) -> Result<Option<Session>, String>;
+    async fn remove_session(&self, access_token: &str) -> Result<Option<Session>, String>;
+
+    async fn insert_refresh_token(
+        &self,
+        refresh_token: &str,
+        grant: RefreshGrant,
+    ) -> Result<(), String>;
+    async fn get_refresh_token(&self, refresh_token: &str) -> Result<Option<RefreshGrant>, String>;
+    async fn remove_refresh_token(
+        &self,
+        refresh_token: &str,
+    ) -> Result<Option<RefreshGrant>, String>;
+
+    async fn insert_nonce(&self, nonce: &str, expires_at: DateTime<Utc>) -> Result<(), String>;
+    async fn remove_nonce(&self, nonce: &str) -> Result<Option<DateTime<Utc>>, String>;
+
+    /// Records the `jti` of a client assertion until `expires_at`, returning false if it is
+    /// recorded already.
+    async fn insert_jti(&self, jti: &str, expires_at: DateTime<Utc>) -> Result<bool, String>;
+
+    /// Removes the expired entries, returning how many. Backends expiring entries on their own
+    /// have nothing to do.
+    async fn purge_expired(&self) -> Result<u64, String> {
+        Ok(0)
+    }
+}
+
+/// The error of a storage refusing new entries, as it holds `max_entries` already.
+pub const STORAGE_FULL: &str = "storage full";
+
+/// The storage managed by rocket.
+pub type Store = Arc<dyn Storage>;
+
+/// Connects the storage of `storage_url`, keeping the state in memory when not configured.
+pub async fn connect(
+    storage_url: Option<&str>,
+    max_entries: Option<usize>,
+    max_nonces: Option<usize>,
+) -> Result<Store, String> {
+    match storage_url {
+        None | Some("memory") => Ok(Arc::new(MemoryStorage {
+            max_entries,
+            max_nonces,
+            ..Default::default()
+        })),
+        #[cfg(feature = "sql")]
+        Some(url) if url.starts_with("sqlite:") || url.starts_with("postgres:") => {
+            Ok(Arc::new(SqlStorage::connect(url).await?))
+        }
+        #[cfg(feature = "redis")]
+        Some(url) if url.starts_with("redis:") => Ok(Arc::new(RedisStorage::connect(url).await?)),
+        Some(url) => Err(format!("unsupported storage url {}", url)),
+    }
+}
+
+/// Manages the storage of `storage_url` once rocket ignites.
+pub fn storage_fairing(config: &Config) -> AdHoc {
+    let storage_url = config.storage_url.clone();
+    let max_entries = Some(config.max_entries.unwrap_or(100_000));
+    let max_nonces = Some(config.max_nonces.unwrap_or(10_000));
+    AdHoc::try_on_ignite("Storage", move |rocket| async move {
+        match connect(storage_url.as_deref(), max_entries, max_nonces).await {
+            Ok(store) => Ok(rocket.manage(store)),
+            Err(err) => {
+                error!("{}", err);
+                Err(rocket)
+            }
+        }
+    })
+}
+
+/// Removes the expired entries of the storage every `sweep_interval` seconds once launched.
+pub fn sweeper_fairing(config: &Config) -> AdHoc {
+    let sweep_interval = config.sweep_interval.unwrap_or(60);
+    AdHoc::on_liftoff("Storage sweeper", move |rocket| {
+        Box::pin(async move {
+            if sweep_interval == 0 {
+                return;
+            }
+            let store = rocket.state::<Store>().unwrap().clone();
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(Duration::from_secs(sweep_interval));
+                loop {
+                    interval.tick().await;
+                    match store.purge_expired().await {
+                        Ok(0) => {}
+                        Ok(purged) => info!("purged {} expired entries", purged),
+                        Err(err) => error!("{}", err),
+                    }
+                }
+            });
+        })
+    })
+}
+
+/// Keeps everything in the memory of the process, the default storage.
+///
+/// With `max_entries` each kind of entry is capped, so new entries are refused with
+/// [`STORAGE_FULL`] while the expired ones are not enough to make room.
+///
+/// Nonces are minted for anyone, so they are capped at `max_nonces` instead and the oldest one
+/// makes room for a new one. A flood of `/authorize` requests then can not lock out logins.
+#[derive(Default)]
+pub struct MemoryStorage {
+    pub codes: Arc<Mutex<HashMap<String, Grant>>>,
+    pub sessions: Arc<Mutex<HashMap<String, Session>>>,
+    pub refresh_tokens: Arc<Mutex<HashMap<String, RefreshGrant>>>,
+    pub nonces: Arc<Mutex<HashMap<String, DateTime<Utc>>>>,
+    pub jtis: Arc<Mutex<HashMap<String, DateTime<Utc>>>>,
+    pub max_entries: Option<usize>,
+    pub max_nonces: Option<usize>,
+}
+
+fn insert_capped<V>(
+    entries: &mut HashMap<String, V>,
+    key: &str,
+    value: V,
+    max_entries: Option<usize>,
+    expires_at: impl Fn(&V) -> DateTime<Utc>,
+) -> Result<(), String> {
+    if let Some(max_entries) = max_entries {
+        if entries.len() >= max_entries && !entries.contains_key(key) {
+            let now = Utc::now();
+            entries.retain(|_, value| expires_at(value) > now);
+            if entries.len() >= max_entries {
+                return Err(STORAGE_FULL.to_string());
+            }
+        }
+    }
+    entries.insert(key.to_string(), value);
+    Ok(())
+}
+
+fn insert_evicting(
+    entries: &Mutex<HashMap<String, DateTime<Utc>>>,
+    key: &str,
+    expires_at: DateTime<Utc>,
+    max_entries: Option<usize>,
+) {
+    let mut entries = entries.lock().unwrap();
+    if let Some(max_entries) = max_entries {
+        if entries.len() >= max_entries && !entries.contains_key(key) {
+            let now = Utc::now();
+            entries.retain(|_, expires_at| *expires_at > now);
+            while entries.len() >= max_entries.max(1) {
+                let oldest = entries
+                    .iter()
+                    .min_by_key(|(_, expires_at)| **expires_at)
+                    .map(|(key, _)| key.clone())
+                    .unwrap();
+                entries.remove(&oldest);
+            }
+        }
+    }
+    entries.insert(key.to_string(), expires_at);
+}
+
+fn purge<V>(entries: &Mutex<HashMap<String, V>>, expires_at: impl Fn(&V) -> DateTime<Utc>) -> u64 {
+    let mut entries = entries.lock().unwrap();
+    let len = entries.len();
+    let now = Utc::now();
+    entries.retain(|_, value| expires_at(value) > now);
+    (len - entries.len()) as u64
+}
+
+#[rocket::async_trait]
+impl Storage for MemoryStorage {
+    async fn insert_code(&self, code: &str, grant: Grant) -> Result<(), String> {
+        insert_capped(
+            &mut self.codes.lock().unwrap(),
+            code,
+            grant,
+            self.max_entries,
+            |grant| grant.expires_at,
+        )
+    }
+
+    async fn get_code(&self, code: &str) -> Result<Option<Grant>, String> {
+        Ok(self.codes.lock().unwrap().get(code).cloned())
+    }
+
+    async fn redeem_code(&self, code: &str) -> Result<bool, String> {
+        match self.codes.lock().unwrap().get_mut(code) {
+            Some(grant) if !grant.redeemed => {
+                grant.redeemed = true;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    async fn remove_code(&self, code: &str) -> Result<(), String> {
+        self.codes.lock().unwrap().remove(code);
+        Ok(())
+    }
+
+    async fn insert_session(&self, access_token: &str, session: Session) -> Result<(), String> {
+        insert_capped(
+            &mut self.sessions.lock().unwrap(),
+            access_token,
+            session,
+            self.max_entries,
+            |session| session.grant.expires_at,
+        )
+    }
+
+    async fn get_session(&self, access_token: &str) -> Result<Option<Session>, String> {
+        Ok(self.sessions.lock().unwrap().get(access_token).cloned())
+    }
+
+    async fn remove_session(&self, access_token: &str) -> Result<Option<Session>, String> {
+        Ok(self.sessions.lock().unwrap().remove(access_token))
+    }
+
+    async fn insert_refresh_token(
+        &self,
+        refresh_token: &str,
+        grant: RefreshGrant,
+    ) -> Result<(), String> {
+        insert_capped(
+            &mut self.refresh_tokens.lock().unwrap(),
+            refresh_token,
+            grant,
+            self.max_entries,
+            |grant| grant.expires_at,
+        )
+    }
+
+    async fn get_refresh_token(&self, refresh_token: &str) -> Result<Option<RefreshGrant>, String> {
+        Ok(self
+            .refresh_tokens
+            .lock()
+            .unwrap()
+            .get(refresh_token)
+            .cloned())
+    }
+
+    async fn remove_refresh_token(
+        &self,
+        refresh_token: &str,
+    ) -> Result<Option<RefreshGrant>, String> {
+        Ok(self.refresh_tokens.lock().unwrap().remove(refresh_token))
+    }
+
+    async fn insert_nonce(&self, nonce: &str, expires_at: DateTime<Utc>) -> Result<(), String> {
+        insert_evicting(&self.nonces, nonce, expires_at, self.max_nonces);
+        Ok(())
+    }
+
+    async fn remove_nonce(&self, nonce: &str) -> Result<Option<DateTime<Utc>>, String> {
+        Ok(self.nonces.lock().unwrap().remove(nonce))
+    }
+
+    async fn insert_jti(&self, jti: &str, expires_at: DateTime<Utc>) -> Result<bool, String> {
+        let mut jtis = self.jtis.lock().unwrap();
+        if matches!(jtis.get(jti), Some(used_until) if *used_until > Utc::now()) {
+            return Ok(false);
+        }
+        insert_capped(&mut jtis, jti, expires_at, self.max_entries, |expires_at| {
+            *expires_at
+        })?;
+        Ok(true)
+    }
+
+    async fn purge_expired(&self) -> Result<u64, String> {
+        Ok(purge(&self.codes, |grant| grant.expires_at)
+            + purge(&self.sessions, |session| session.grant.expires_at)
+            + purge(&self.refresh_tokens, |grant| grant.expires_at)
+            + purge(&self.nonces, |expires_at| *expires_at)
+            + purge(&self.jtis, |expires_at| *expires_at))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    fn grant(expires_at: DateTime<Utc>) -> Grant {
+        Grant {
+            client_id: "foo".into(),
+            redirect_uri: "https://example.com/".into(),
+            access_token: "access".into(),
+            code_challenge: None,
+            code_challenge_method: None,
+            expires_at,
+            redeemed: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_redeem_code_once() {
+        let storage = MemoryStorage::default();
+        let grant = grant(Utc::now() + Duration::minutes(1));
+        storage.insert_code("code", grant.clone()).await.unwrap();
+        assert_eq!(storage.get_code("code").await.unwrap(), Some(grant));
+        assert!(storage.redeem_code("code").await.unwrap());
+        assert!(!storage.redeem_code("code").await.unwrap());
+        assert!(!storage.redeem_code("unknown").await.unwrap());
+        storage.remove_code("code").await.unwrap();
+        assert_eq!(storage.get_code("code").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_purge_expired() {
+        let storage = MemoryStorage::default();
+        storage
+            .insert_code("expired", grant(Utc::now() - Duration::seconds(1)))
+            .await
+            .unwrap();
+        storage
+            .insert_code("valid", grant(Utc::now() + Duration::minutes(1)))
+            .await
+            .unwrap();
+        storage
+            .insert_nonce("expired", Utc::now() - Duration::seconds(1))
+            .await
+            .unwrap();
+        assert_eq!(storage.purge_expired().await.unwrap(), 2);
+        assert_eq!(storage.get_code("expired").await.unwrap(), None);
+        assert!(storage.get_code("valid").await.unwrap().is_some());
+        assert_eq!(storage.remove_nonce("expired").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_max_entries() {
+        let storage = MemoryStorage {
+            max_entries: Some(2),
+            ..Default::default()
+        };
+        let expires_at = Utc::now() + Duration::minutes(1);
+        storage.insert_code("a", grant(expires_at)).await.unwrap();
+        storage
+            .insert_code("b", grant(Utc::now() - Duration::seconds(1)))
+            .await
+            .unwrap();
+        // the expired code makes room
+        storage.insert_code("c", grant(expires_at)).await.unwrap();
+        assert_eq!(
+            storage.insert_code("d", grant(expires_at)).await,
+            Err(STORAGE_FULL.to_string())
+        );
+        // replacing an entry needs no room
+        storage.insert_code("c", grant(expires_at)).await.unwrap();
+        storage.remove_code("a").await.unwrap();
+        storage.insert_code("d", grant(expires_at)).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_jtis() {
+        let storage = MemoryStorage::default();
+        let expires_at = Utc::now() + Duration::minutes(1);
+        assert!(storage.insert_jti("a", expires_at).await.unwrap());
+        assert!(!storage.insert_jti("a", expires_at).await.unwrap());
+        assert!(storage
+            .insert_jti("b", Utc::now() - Duration::seconds(1))
+            .await
+            .unwrap());
+        // an expired jti is of no use anymore, as the assertion expired as well
+        assert!(storage.insert_jti("b", expires_at).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_max_nonces() {
+        let storage = MemoryStorage {
+            max_nonces: Some(2),
+            ..Default::default()
+        };
+        let now = Utc::now();
+        storage
+            .insert_nonce("a", now + Duration::minutes(1))
+            .await
+            .unwrap();
+        storage
+            .insert_nonce("b", now + Duration::minutes(2))
+            .await
+            .unwrap();
+        // the oldest nonce makes room
+        storage
+            .insert_nonce("c", now + Duration::minutes(3))
+            .await
+            .unwrap();
+        assert_eq!(storage.remove_nonce("a").await.unwrap(), None);
+        assert!(storage.remove_nonce("b").await.unwrap().is_some());
+        assert!(storage.remove_nonce("c").await.unwrap().is_some());
+    }
+}
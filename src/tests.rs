@@ -27,3 +27,211 @@ mod jwk_test {
         assert_eq!(response.status(), Status::Ok);
     }
 }
+
+#[cfg(test)]
+pub mod wallet {
+    use secp256k1::SecretKey;
+    use web3::signing::{Key, SecretKeyRef};
+
+    use crate::web3::eth_message;
+
+    pub fn key() -> SecretKey {
+        SecretKey::from_slice(&[0x42; 32]).unwrap()
+    }
+
+    pub fn address(key: &SecretKey) -> String {
+        format!("{:02X?}", SecretKeyRef::new(key).address())
+    }
+
+    pub fn sign(key: &SecretKey, message: &str) -> String {
+        sign_hash(key, &eth_message(message.to_string()))
+    }
+
+    pub fn sign_hash(key: &SecretKey, hash: &[u8; 32]) -> String {
+        let signature = SecretKeyRef::new(key).sign(hash, None).unwrap();
+        format!(
+            "0x{}{}{:02x}",
+            hex::encode(signature.r),
+            hex::encode(signature.s),
+            signature.v
+        )
+    }
+}
+
+#[cfg(test)]
+pub fn issue_challenge(client: &rocket::local::blocking::Client, challenge: &str) {
+    use chrono::{Duration, Utc};
+
+    let store = client.rocket().state::<crate::storage::Store>().unwrap();
+    futures::executor::block_on(store.insert_nonce(challenge, Utc::now() + Duration::minutes(5)))
+        .unwrap();
+}
+
+#[cfg(test)]
+pub fn issue_code(
+    client: &rocket::local::blocking::Client,
+    client_id: &str,
+    redirect_uri: &str,
+) -> String {
+    use crate::access_token::issue_access_token;
+    use crate::claims::{additional_claims, standard_claims};
+    use crate::config::Config;
+    use crate::keys::Keys;
+    use crate::refresh::issue_refresh_token;
+    use crate::storage::Store;
+    use crate::token::{store_token, token, AccessGrant, Grant};
+    use crate::web3::OwnershipCondition;
+    use futures::executor::block_on;
+    use openidconnect::AuthorizationCode;
+    use uuid::Uuid;
+
+    let account = "0x9c9e8eabd947658bdb713e0d3ebfe56860abdb8d".to_string();
+    let code = AuthorizationCode::new(Uuid::new_v4().to_string());
+    let config = client.rocket().state::<Config>().unwrap();
+    let keys = client.rocket().state::<Keys>().unwrap();
+    let standard_claims = standard_claims(&account);
+    let additional_claims = additional_claims(
+        &account,
+        &"dotzxrenodo".to_string(),
+        &"".to_string(),
+        &65,
+        &"".to_string(),
+        &"0x886B6781CD7dF75d8440Aba84216b2671AEFf9A4".to_string(),
+        &None,
+        &"1".to_string(),
+        &[],
+    );
+    let store = client.rocket().state::<Store>().unwrap();
+    let access_grant = AccessGrant {
+        client_id: client_id.to_string(),
+        realm: "okt".to_string(),
+        chain: "okt".to_string(),
+        condition: OwnershipCondition::new(
+            "0x886B6781CD7dF75d8440Aba84216b2671AEFf9A4".to_string(),
+        ),
+        expires_at: chrono::Utc::now() + chrono::Duration::hours(1),
+    };
+    let access_token = issue_access_token(config, keys, &access_grant, &additional_claims);
+    let mut token = block_on(token(
+        config,
+        keys,
+        "okt".to_string(),
+        client_id.to_string(),
+        None,
+        standard_claims.clone(),
+        additional_claims.clone(),
+        access_token.clone(),
+        Some(code.clone()),
+    ));
+    token.set_refresh_token(Some(
+        block_on(issue_refresh_token(
+            config,
+            store,
+            &access_token,
+            &access_grant,
+            &standard_claims,
+            &additional_claims,
+        ))
+        .unwrap(),
+    ));
+
+    block_on(store.insert_code(
+        code.secret(),
+        Grant {
+            client_id: client_id.to_string(),
+            redirect_uri: url::Url::parse(redirect_uri).unwrap().to_string(),
+            access_token: access_token.secret().clone(),
+            code_challenge: None,
+            code_challenge_method: None,
+            expires_at: chrono::Utc::now() + chrono::Duration::minutes(1),
+            redeemed: false,
+        },
+    ))
+    .unwrap();
+    block_on(store_token(
+        store,
+        access_grant,
+        token,
+        standard_claims,
+        additional_claims,
+    ))
+    .unwrap();
+    code.secret().clone()
+}
+
+#[cfg(test)]
+pub fn update_code(
+    client: &rocket::local::blocking::Client,
+    code: &str,
+    update: impl FnOnce(&mut crate::token::Grant),
+) {
+    use futures::executor::block_on;
+
+    let store = client.rocket().state::<crate::storage::Store>().unwrap();
+    let mut grant = block_on(store.get_code(code)).unwrap().unwrap();
+    update(&mut grant);
+    block_on(store.insert_code(code, grant)).unwrap();
+}
+
+#[cfg(test)]
+pub fn update_session(
+    client: &rocket::local::blocking::Client,
+    access_token: &str,
+    update: impl FnOnce(&mut crate::storage::Session),
+) {
+    use futures::executor::block_on;
+
+    let store = client.rocket().state::<crate::storage::Store>().unwrap();
+    let mut session = block_on(store.get_session(access_token)).unwrap().unwrap();
+    update(&mut session);
+    block_on(store.insert_session(access_token, session)).unwrap();
+}
+
+#[cfg(test)]
+pub mod jwe {
+    use crate::jose::NftJweContentEncryptionAlgorithm;
+    use crate::jwe::aead_algorithm;
+    use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey};
+    use rsa::pkcs1::DecodeRsaPrivateKey;
+    use rsa::{PaddingScheme, RsaPrivateKey};
+    use serde_json::Value;
+
+    fn decode(segment: &str) -> Vec<u8> {
+        base64::decode_config(segment, base64::URL_SAFE_NO_PAD).unwrap()
+    }
+
+    pub fn header(jwe: &str) -> Value {
+        serde_json::from_slice(&decode(jwe.split('.').next().unwrap())).unwrap()
+    }
+
+    /// Decrypts the content of a JWE with the content encryption key.
+    pub fn open(jwe: &str, cek: &[u8]) -> Vec<u8> {
+        let segments: Vec<&str> = jwe.split('.').collect();
+        let enc: NftJweContentEncryptionAlgorithm =
+            serde_json::from_value(header(jwe).get("enc").unwrap().clone()).unwrap();
+        let key = LessSafeKey::new(UnboundKey::new(aead_algorithm(&enc), cek).unwrap());
+        let mut in_out = [decode(segments[3]), decode(segments[4])].concat();
+        let iv: [u8; 12] = decode(segments[2]).try_into().unwrap();
+        key.open_in_place(
+            Nonce::assume_unique_for_key(iv),
+            Aad::from(segments[0].as_bytes()),
+            &mut in_out,
+        )
+        .unwrap()
+        .to_vec()
+    }
+
+    /// Decrypts a JWE encrypted by RSA-OAEP for `do-not-use-client.pem`.
+    pub fn decrypt(jwe: &str) -> Vec<u8> {
+        let private_key =
+            RsaPrivateKey::from_pkcs1_pem(include_str!("../do-not-use-client.pem")).unwrap();
+        let padding = match header(jwe).get("alg").unwrap().as_str().unwrap() {
+            "RSA-OAEP-256" => PaddingScheme::new_oaep::<sha2::Sha256>(),
+            _ => PaddingScheme::new_oaep::<sha1::Sha1>(),
+        };
+        let cek = private_key
+            .decrypt(padding, &decode(jwe.split('.').nth(1).unwrap()))
+            .unwrap();
+        open(jwe, &cek)
+    }
+}
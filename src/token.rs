@@ -1,105 +1,381 @@
-use chrono::{Duration, Utc};
-use openidconnect::core::{
-    CoreGenderClaim, CoreJsonWebKeyType, CoreJweContentEncryptionAlgorithm,
-    CoreJwsSigningAlgorithm, CoreRsaPrivateSigningKey, CoreTokenType,
-};
+use chrono::{DateTime, Duration, Utc};
+use openidconnect::core::{CoreErrorResponseType, CoreGenderClaim, CoreTokenType};
 use openidconnect::{
     AccessToken, Audience, AuthorizationCode, EmptyExtraTokenFields, IdToken, IdTokenClaims,
-    IdTokenFields, IssuerUrl, JsonWebKeyId, StandardClaims, StandardTokenResponse,
+    IdTokenFields, IssuerUrl, OAuth2TokenResponse, StandardClaims, StandardErrorResponse,
+    StandardTokenResponse, TokenResponse,
 };
 use rocket::form::Form;
-use rocket::response::status::NotFound;
+use rocket::http::Status;
 use rocket::serde::json::Json;
 use rocket::State;
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use url::Url;
 
 use crate::claims::Claims;
-use crate::config::Config;
+use crate::client_auth::{auth_method, authenticate_client, BasicAuth, ClientCredentials};
+use crate::config::{get_client, Config};
+use crate::jose::{NftJsonWebKeyType, NftJweContentEncryptionAlgorithm, NftJwsSigningAlgorithm};
+use crate::jwe::encrypt_id_token;
+use crate::keys::Keys;
+use crate::pkce::verify_code_verifier;
+use crate::refresh::refresh_token_grant;
+use crate::storage::{Session, Store};
+use crate::web3::OwnershipCondition;
+
+/// An authorization code grant, bound to the client, redirect uri and PKCE challenge it was issued for.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Grant {
+    pub client_id: String,
+    pub redirect_uri: String,
+    pub access_token: String,
+    pub code_challenge: Option<String>,
+    pub code_challenge_method: Option<String>,
+    pub expires_at: DateTime<Utc>,
+    pub redeemed: bool,
+}
 
-pub struct Tokens {
-    pub muted: Arc<Mutex<HashMap<String, NftTokenResponse>>>,
-    pub bearer: Arc<Mutex<HashMap<String, String>>>,
+/// An access token, bound to the client and the ownership condition it was issued for.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AccessGrant {
+    pub client_id: String,
+    pub realm: String,
+    pub chain: String,
+    pub condition: OwnershipCondition,
+    pub expires_at: DateTime<Utc>,
 }
 
 #[derive(FromForm)]
 pub struct PostData {
     pub grant_type: Option<String>,
-    pub code: String,
+    pub code: Option<String>,
     pub client_id: Option<String>,
     pub client_secret: Option<String>,
-    pub redirect_uri: String,
+    pub client_assertion_type: Option<String>,
+    pub client_assertion: Option<String>,
+    pub redirect_uri: Option<String>,
+    pub code_verifier: Option<String>,
+    pub refresh_token: Option<String>,
 }
 
-#[get("/token?<code>")]
+pub type TokenError = (Status, Json<StandardErrorResponse<CoreErrorResponseType>>);
+
+/// An error response of the token endpoint as in RFC 6749 section 5.2.
+pub fn token_error(error: CoreErrorResponseType, error_description: &str) -> TokenError {
+    let status = match &error {
+        CoreErrorResponseType::InvalidClient => Status::Unauthorized,
+        CoreErrorResponseType::Extension(error) if error == "temporarily_unavailable" => {
+            Status::ServiceUnavailable
+        }
+        CoreErrorResponseType::Extension(error) if error == "server_error" => {
+            Status::InternalServerError
+        }
+        _ => Status::BadRequest,
+    };
+    (
+        status,
+        Json(StandardErrorResponse::new(
+            error,
+            Some(error_description.to_string()),
+            None,
+        )),
+    )
+}
+
+/// A failure of the storage, answered as `server_error`.
+pub fn storage_error(error: String) -> TokenError {
+    token_error(
+        CoreErrorResponseType::Extension("server_error".to_string()),
+        &error,
+    )
+}
+
+fn check_code_verifier(grant: &Grant, code_verifier: Option<&str>) -> Result<(), TokenError> {
+    match (&grant.code_challenge, &grant.code_challenge_method) {
+        (Some(code_challenge), Some(code_challenge_method)) => match code_verifier {
+            Some(code_verifier)
+                if verify_code_verifier(code_challenge, code_challenge_method, code_verifier) =>
+            {
+                Ok(())
+            }
+            Some(_) => Err(token_error(
+                CoreErrorResponseType::InvalidGrant,
+                "code verifier mismatch",
+            )),
+            None => Err(token_error(
+                CoreErrorResponseType::InvalidRequest,
+                "code_verifier missing",
+            )),
+        },
+        _ => Ok(()),
+    }
+}
+
+/// Seconds an access token is valid, one hour by default.
+pub fn access_ttl(config: &Config) -> i64 {
+    config.access_ttl.unwrap_or(3600)
+}
+
+/// Stores an issued token and its claims, so they can be resolved by the access token.
+pub async fn store_token(
+    store: &Store,
+    grant: AccessGrant,
+    token: NftTokenResponse,
+    standard_claims: StandardClaims<CoreGenderClaim>,
+    additional_claims: Claims,
+) -> Result<(), String> {
+    let access_token = token.access_token().secret().clone();
+    store
+        .insert_session(
+            &access_token,
+            Session {
+                grant,
+                token,
+                standard_claims,
+                additional_claims,
+            },
+        )
+        .await
+}
+
+/// Removes the tokens issued with the access token and their claims.
+pub async fn revoke(store: &Store, access_token: &str) -> Result<(), String> {
+    if let Some(session) = store.remove_session(access_token).await? {
+        if let Some(refresh_token) = session.token.refresh_token() {
+            store.remove_refresh_token(refresh_token.secret()).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Redeems a code once, after `verify` accepted its grant.
+///
+/// A second redemption revokes the tokens issued from the code, as in RFC 6749 section 4.1.2.
+/// It has to pass `verify` as well, so a leaked code alone can not revoke the tokens of the user.
+async fn redeem_code(
+    store: &Store,
+    code: &str,
+    verify: impl FnOnce(&Grant) -> Result<(), TokenError>,
+) -> Result<Grant, TokenError> {
+    let grant = match store.get_code(code).await.map_err(storage_error)? {
+        Some(grant) => grant,
+        None => {
+            return Err(token_error(
+                CoreErrorResponseType::InvalidGrant,
+                "invalid code",
+            ))
+        }
+    };
+    if grant.expires_at <= Utc::now() && !grant.redeemed {
+        store.remove_code(code).await.map_err(storage_error)?;
+        return Err(token_error(
+            CoreErrorResponseType::InvalidGrant,
+            "code expired",
+        ));
+    }
+    verify(&grant)?;
+    // redeeming is atomic in the storage, so only one of concurrent requests gets the tokens
+    if grant.redeemed || !store.redeem_code(code).await.map_err(storage_error)? {
+        store.remove_code(code).await.map_err(storage_error)?;
+        revoke(store, &grant.access_token)
+            .await
+            .map_err(storage_error)?;
+        return Err(token_error(
+            CoreErrorResponseType::InvalidGrant,
+            "code already used",
+        ));
+    }
+    Ok(grant)
+}
+
+async fn issued_token(store: &Store, access_token: &str) -> Result<NftTokenResponse, TokenError> {
+    match store
+        .get_session(access_token)
+        .await
+        .map_err(storage_error)?
+    {
+        Some(session) => Ok(session.token),
+        None => Err(token_error(
+            CoreErrorResponseType::InvalidGrant,
+            "invalid code",
+        )),
+    }
+}
+
+/// The token response, with the id token encrypted for clients with
+/// `id_token_encrypted_response_alg`.
+fn token_response(
+    config: &Config,
+    client_id: &str,
+    token: NftTokenResponse,
+) -> Result<Json<Value>, TokenError> {
+    let mut response = serde_json::to_value(&token).unwrap();
+    if let Some(id_token) = token.id_token() {
+        let id_token = encrypt_id_token(get_client(config, client_id), id_token.to_string())
+            .map_err(|err| {
+                token_error(
+                    CoreErrorResponseType::Extension("server_error".to_string()),
+                    &err,
+                )
+            })?;
+        response["id_token"] = Value::String(id_token);
+    }
+    Ok(Json(response))
+}
+
+#[get("/token?<code>&<code_verifier>")]
 pub async fn default_token_endpoint(
-    tokens: &State<Tokens>,
+    config: &State<Config>,
+    store: &State<Store>,
     code: String,
-) -> Result<Json<NftTokenResponse>, NotFound<String>> {
-    token_endpoint(tokens, "default".into(), code).await
+    code_verifier: Option<String>,
+) -> Result<Json<Value>, TokenError> {
+    token_endpoint(config, store, "default".into(), code, code_verifier).await
 }
 
+/// Redeems a code without client authentication, which is only possible for public clients.
+///
+/// Not mounted when `get_token_endpoint` is disabled.
 #[allow(unused_variables)]
-#[get("/<realm>/token?<code>")]
+#[get("/<realm>/token?<code>&<code_verifier>")]
 pub async fn token_endpoint(
-    tokens: &State<Tokens>,
+    config: &State<Config>,
+    store: &State<Store>,
     realm: String,
     code: String,
-) -> Result<Json<NftTokenResponse>, NotFound<String>> {
-    let mutex = tokens.bearer.lock().unwrap();
-    let access_token = mutex.get(&code);
-    if access_token.is_none() {
-        return Err(NotFound("Invalid Code".to_string()));
-    }
-    let access_token = access_token.unwrap();
-    let mutex = tokens.muted.lock().unwrap();
-    let token = mutex.get(access_token);
-    match token {
-        Some(token) => Ok(Json(token.clone())),
-        _ => Err(NotFound("Invalid Code".to_string())),
-    }
+    code_verifier: Option<String>,
+) -> Result<Json<Value>, TokenError> {
+    let grant = redeem_code(store, &code, |grant| {
+        match get_client(config, &grant.client_id).map(auth_method) {
+            Some(method) if method == "none" => {}
+            _ => {
+                return Err(token_error(
+                    CoreErrorResponseType::InvalidClient,
+                    "client authentication required",
+                ))
+            }
+        }
+        check_code_verifier(grant, code_verifier.as_deref())
+    })
+    .await?;
+    let token = issued_token(store, &grant.access_token).await?;
+    token_response(config, &grant.client_id, token)
 }
 
 #[post("/token", data = "<post_data>")]
 pub async fn default_post_token_endpoint(
-    tokens: &State<Tokens>,
+    config: &State<Config>,
+    keys: &State<Keys>,
+    store: &State<Store>,
+    basic_auth: BasicAuth,
     post_data: Form<PostData>,
-) -> Result<Json<NftTokenResponse>, NotFound<String>> {
-    default_token_endpoint(tokens, post_data.code.clone()).await
+) -> Result<Json<Value>, TokenError> {
+    post_token_endpoint(config, keys, store, basic_auth, "default".into(), post_data).await
 }
 
-#[allow(unused_variables)]
 #[post("/<realm>/token", data = "<post_data>")]
 pub async fn post_token_endpoint(
-    tokens: &State<Tokens>,
+    config: &State<Config>,
+    keys: &State<Keys>,
+    store: &State<Store>,
+    basic_auth: BasicAuth,
     realm: String,
     post_data: Form<PostData>,
-) -> Result<Json<NftTokenResponse>, NotFound<String>> {
-    token_endpoint(tokens, "default".into(), post_data.code.clone()).await
+) -> Result<Json<Value>, TokenError> {
+    let post_data = post_data.into_inner();
+
+    match post_data.grant_type.as_deref() {
+        Some("authorization_code") | Some("refresh_token") => {}
+        Some(_) => {
+            return Err(token_error(
+                CoreErrorResponseType::UnsupportedGrantType,
+                "grant type not supported",
+            ))
+        }
+        None => {
+            return Err(token_error(
+                CoreErrorResponseType::InvalidRequest,
+                "grant_type missing",
+            ))
+        }
+    }
+
+    let credentials = ClientCredentials {
+        basic: basic_auth.0,
+        client_id: post_data.client_id,
+        client_secret: post_data.client_secret,
+        client_assertion_type: post_data.client_assertion_type,
+        client_assertion: post_data.client_assertion,
+    };
+    let client_id = authenticate_client(config, store, &realm, &credentials)
+        .await
+        .map_err(|err| token_error(CoreErrorResponseType::InvalidClient, &err))?;
+
+    if post_data.grant_type.as_deref() == Some("refresh_token") {
+        let refresh_token = post_data.refresh_token.ok_or_else(|| {
+            token_error(
+                CoreErrorResponseType::InvalidRequest,
+                "refresh_token missing",
+            )
+        })?;
+        let token = refresh_token_grant(config, keys, store, &client_id, &refresh_token).await?;
+        return token_response(config, &client_id, token);
+    }
+
+    let code = post_data
+        .code
+        .ok_or_else(|| token_error(CoreErrorResponseType::InvalidRequest, "code missing"))?;
+
+    let redirect_uri = post_data
+        .redirect_uri
+        .and_then(|redirect_uri| Url::parse(&redirect_uri).ok())
+        .map(|redirect_uri| redirect_uri.to_string());
+
+    let grant = redeem_code(store, &code, |grant| {
+        if grant.client_id != client_id {
+            return Err(token_error(
+                CoreErrorResponseType::InvalidGrant,
+                "code was issued to another client",
+            ));
+        }
+        if redirect_uri.as_ref() != Some(&grant.redirect_uri) {
+            return Err(token_error(
+                CoreErrorResponseType::InvalidGrant,
+                "redirect uri mismatch",
+            ));
+        }
+        check_code_verifier(grant, post_data.code_verifier.as_deref())
+    })
+    .await?;
+
+    let token = issued_token(store, &grant.access_token).await?;
+    token_response(config, &client_id, token)
 }
 
 pub type NftIdTokenFields = IdTokenFields<
     Claims,
     EmptyExtraTokenFields,
     CoreGenderClaim,
-    CoreJweContentEncryptionAlgorithm,
-    CoreJwsSigningAlgorithm,
-    CoreJsonWebKeyType,
+    NftJweContentEncryptionAlgorithm,
+    NftJwsSigningAlgorithm,
+    NftJsonWebKeyType,
 >;
 
 pub type NftTokenResponse = StandardTokenResponse<NftIdTokenFields, CoreTokenType>;
 
 pub async fn token(
     config: &Config,
+    keys: &Keys,
     realm: String,
     client_id: String,
     _nonce: Option<String>,
     standard_claims: StandardClaims<CoreGenderClaim>,
     additional_claims: Claims,
     access_token: AccessToken,
-    code: AuthorizationCode,
+    code: Option<AuthorizationCode>,
 ) -> NftTokenResponse {
-    let rsa_pem = config.rsa_pem.clone();
+    let signing_key = keys.for_realm(config, &realm);
     let id_token = IdToken::new(
         IdTokenClaims::new(
             IssuerUrl::new(format!("{}/{}", config.ext_hostname, realm)).unwrap(),
@@ -109,19 +385,11 @@ pub async fn token(
             standard_claims,
             additional_claims,
         ),
-        // The private key used for signing the ID token. For confidential clients (those able
-        // to maintain a client secret), a CoreHmacKey can also be used, in conjunction
-        // with one of the CoreJwsSigningAlgorithm::HmacSha* signing algorithms. When using an
-        // HMAC-based signing algorithm, the UTF-8 representation of the client secret should
-        // be used as the HMAC key.
-        &CoreRsaPrivateSigningKey::from_pem(
-            &rsa_pem.unwrap_or_default(),
-            Some(JsonWebKeyId::new(config.key_id.to_string())),
-        )
-        .expect("Invalid RSA private key"),
-        // Uses the RS256 signature algorithm. This crate supports any RS*, PS*, or HS*
-        // signature algorithm.
-        CoreJwsSigningAlgorithm::RsaSsaPkcs1V15Sha256,
+        // The private key used for signing the ID token, the active key of the algorithm of
+        // the realm.
+        signing_key.key.as_ref(),
+        // Uses the signature algorithm of the realm, RS256, ES256 or EdDSA.
+        signing_key.key.alg(),
         // When returning the ID token alongside an access token (e.g., in the Authorization Code
         // flow), it is recommended to pass the access token here to set the `at_hash` claim
         // automatically.
@@ -129,22 +397,34 @@ pub async fn token(
         // When returning the ID token alongside an authorization code (e.g., in the implicit
         // flow), it is recommended to pass the authorization code here to set the `c_hash` claim
         // automatically.
-        Some(&code),
+        code.as_ref(),
     )
     .unwrap();
 
-    NftTokenResponse::new(
+    let mut token = NftTokenResponse::new(
         access_token,
         CoreTokenType::Bearer,
         NftIdTokenFields::new(Some(id_token), EmptyExtraTokenFields {}),
-    )
+    );
+    token.set_expires_in(Some(&std::time::Duration::from_secs(
+        access_ttl(config) as u64
+    )));
+    token
 }
 
 #[cfg(test)]
 mod tests {
+    use super::{Config, Keys, NftJwsSigningAlgorithm};
+    use crate::claims::{additional_claims, standard_claims};
+    use crate::jose::NftJsonWebKeySet;
     use crate::rocket;
-    use rocket::http::Status;
-    use rocket::local::blocking::Client;
+    use crate::storage::Store;
+    use crate::tests::{issue_challenge, issue_code, update_code};
+    use chrono::{Duration, Utc};
+    use futures::executor::block_on;
+    use openidconnect::{AccessToken, ClientId, IdTokenVerifier, IssuerUrl, Nonce};
+    use rocket::http::{ContentType, Header, Status};
+    use rocket::local::blocking::{Client, LocalResponse};
     use serde_json::Value;
     use std::collections::HashMap;
     use url::Url;
@@ -158,11 +438,12 @@ mod tests {
         let signature = "0x87b709d1e84aab056cf089af31e8d7c891d6f363663ff3eeb4bbb4c4e0602b2e3edf117fe548626b8d83e3b2c530cb55e2baff29ca54dbd495bb45764d9aa44c1c".to_string();
 
         let client = Client::tracked(rocket()).expect("valid rocket instance");
+        issue_challenge(&client, &nonce);
 
         let response = client
             .get(format!(
-                "/authorize?client_id={}&realm=okt&redirect_uri=https://example.com&nonce={}&contract={}&account={}&signature={}",
-                client_id, nonce, contract, account, signature
+                "/authorize?client_id={}&realm=okt&redirect_uri=https://example.com&nonce={}&contract={}&account={}&signature={}&challenge={}",
+                client_id, nonce, contract, account, signature, nonce
             ))
             .dispatch();
         assert_eq!(response.status(), Status::TemporaryRedirect);
@@ -179,17 +460,309 @@ mod tests {
 
         assert!(params.get("code").is_some());
         let code = params.get("code").unwrap();
-        let response = client.get(format!("/token?code={}", code)).dispatch();
-        assert_eq!(response.status(), Status::Ok);
         let response = client.get(format!("/okt/token?code={}", code)).dispatch();
         assert_eq!(response.status(), Status::Ok);
         let token = response.into_json::<Value>().unwrap();
         let access_token = token.get("access_token");
         assert!(access_token.is_some());
+        let response = client.get(format!("/token?code={}", code)).dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
 
         let response = client
             .get(format!("/token?code={}", "invalid".to_string()))
             .dispatch();
-        assert_eq!(response.status(), Status::NotFound);
+        assert_eq!(response.status(), Status::BadRequest);
+    }
+
+    #[test]
+    fn token_signing_alg() {
+        let client = Client::tracked(rocket()).expect("valid rocket instance");
+        let config = client.rocket().state::<Config>().unwrap();
+        let keys = client.rocket().state::<Keys>().unwrap();
+        let account = "0x9c9e8eabd947658bdb713e0d3ebfe56860abdb8d".to_string();
+        for (realm, alg) in [
+            ("okt", NftJwsSigningAlgorithm::RsaSsaPkcs1V15Sha256),
+            ("clv", NftJwsSigningAlgorithm::EcdsaP256Sha256),
+            ("heco", NftJwsSigningAlgorithm::EdDsa),
+            ("evmos", NftJwsSigningAlgorithm::RsaSsaPkcs1V15Sha256),
+        ] {
+            let additional_claims = additional_claims(
+                &account,
+                &"dotzxrenodo".to_string(),
+                &"".to_string(),
+                &65,
+                &"".to_string(),
+                &"0x886B6781CD7dF75d8440Aba84216b2671AEFf9A4".to_string(),
+                &None,
+                &"1".to_string(),
+                &[],
+            );
+            let token = block_on(super::token(
+                config,
+                keys,
+                realm.to_string(),
+                "foo".to_string(),
+                None,
+                standard_claims(&account),
+                additional_claims,
+                AccessToken::new("access".to_string()),
+                None,
+            ));
+            let id_token = token.extra_fields().id_token().unwrap();
+
+            let jwks: NftJsonWebKeySet = client
+                .get(format!("/{}/jwk", realm))
+                .dispatch()
+                .into_json()
+                .unwrap();
+            let verifier = IdTokenVerifier::new_public_client(
+                ClientId::new("foo".to_string()),
+                IssuerUrl::new(format!("http://localhost:8000/{}", realm)).unwrap(),
+                jwks,
+            )
+            .set_allowed_algs(vec![alg]);
+            let claims = id_token
+                .claims(&verifier, |_: Option<&Nonce>| Ok(()))
+                .unwrap();
+            assert_eq!(claims.subject().as_str(), account);
+        }
+    }
+
+    fn error(response: LocalResponse) -> String {
+        let error = response.into_json::<Value>().unwrap();
+        error.get("error").unwrap().as_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn post_token_public_client() {
+        let client = Client::tracked(rocket()).expect("valid rocket instance");
+        let code = issue_code(&client, "foo", "https://example.com");
+
+        let response = client
+            .post("/okt/token")
+            .header(ContentType::Form)
+            .body(format!(
+                "grant_type=authorization_code&code={}&redirect_uri=https://example.com/callback&client_id=foo",
+                code
+            ))
+            .dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+        assert_eq!(error(response), "invalid_grant");
+
+        let response = client
+            .post("/okt/token")
+            .header(ContentType::Form)
+            .body(format!(
+                "grant_type=authorization_code&code={}&redirect_uri=https://example.com&client_id=bar",
+                code
+            ))
+            .dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+        assert_eq!(error(response), "invalid_grant");
+
+        let response = client
+            .post("/okt/token")
+            .header(ContentType::Form)
+            .body(format!(
+                "grant_type=password&code={}&redirect_uri=https://example.com&client_id=foo",
+                code
+            ))
+            .dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+        assert_eq!(error(response), "unsupported_grant_type");
+
+        let response = client
+            .post("/okt/token")
+            .header(ContentType::Form)
+            .body(format!(
+                "grant_type=authorization_code&code={}&redirect_uri=https://example.com&client_id=foo",
+                code
+            ))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let token = response.into_json::<Value>().unwrap();
+        assert!(token.get("access_token").is_some());
+    }
+
+    #[test]
+    fn code_reuse_revokes_tokens() {
+        let client = Client::tracked(rocket()).expect("valid rocket instance");
+        let code = issue_code(&client, "foo", "https://example.com");
+        let body = format!(
+            "grant_type=authorization_code&code={}&redirect_uri=https://example.com&client_id=foo",
+            code
+        );
+
+        let response = client
+            .post("/okt/token")
+            .header(ContentType::Form)
+            .body(body.clone())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let token = response.into_json::<Value>().unwrap();
+        let access_token = token.get("access_token").unwrap().as_str().unwrap();
+
+        let response = client
+            .post("/okt/token")
+            .header(ContentType::Form)
+            .body(body)
+            .dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+        assert_eq!(error(response), "invalid_grant");
+
+        let store = client.rocket().state::<Store>().unwrap();
+        assert!(block_on(store.get_session(access_token)).unwrap().is_none());
+        assert!(block_on(store.get_code(&code)).unwrap().is_none());
+    }
+
+    #[test]
+    fn expired_code() {
+        let client = Client::tracked(rocket()).expect("valid rocket instance");
+        let code = issue_code(&client, "foo", "https://example.com");
+        update_code(&client, &code, |grant| {
+            grant.expires_at = Utc::now() - Duration::seconds(1);
+        });
+
+        let response = client.get(format!("/okt/token?code={}", code)).dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+        assert_eq!(error(response), "invalid_grant");
+    }
+
+    #[test]
+    fn post_token_pkce() {
+        let client = Client::tracked(rocket()).expect("valid rocket instance");
+        let code = issue_code(&client, "foo", "https://example.com");
+        update_code(&client, &code, |grant| {
+            grant.code_challenge = Some("E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM".into());
+            grant.code_challenge_method = Some("S256".into());
+        });
+        let body = format!(
+            "grant_type=authorization_code&code={}&redirect_uri=https://example.com&client_id=foo",
+            code
+        );
+
+        let response = client
+            .post("/okt/token")
+            .header(ContentType::Form)
+            .body(body.clone())
+            .dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+        assert_eq!(error(response), "invalid_request");
+
+        let response = client
+            .post("/okt/token")
+            .header(ContentType::Form)
+            .body(format!(
+                "{}&code_verifier=eBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk",
+                body
+            ))
+            .dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+        assert_eq!(error(response), "invalid_grant");
+
+        let response = client.get(format!("/okt/token?code={}", code)).dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+
+        let response = client
+            .post("/okt/token")
+            .header(ContentType::Form)
+            .body(format!(
+                "{}&code_verifier=dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk",
+                body
+            ))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+    }
+
+    #[test]
+    fn post_token_confidential_client() {
+        let client = Client::tracked(rocket()).expect("valid rocket instance");
+        let code = issue_code(&client, "confidential", "https://example.com");
+        let body = format!(
+            "grant_type=authorization_code&code={}&redirect_uri=https://example.com",
+            code
+        );
+
+        let response = client.get(format!("/okt/token?code={}", code)).dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+        assert_eq!(error(response), "invalid_client");
+
+        let response = client
+            .post("/okt/token")
+            .header(ContentType::Form)
+            .body(format!("{}&client_id=confidential", body))
+            .dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+        assert_eq!(error(response), "invalid_client");
+
+        let response = client
+            .post("/okt/token")
+            .header(ContentType::Form)
+            .body(format!(
+                "{}&client_id=confidential&client_secret=secret",
+                body
+            ))
+            .dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+
+        let response = client
+            .post("/okt/token")
+            .header(ContentType::Form)
+            .header(Header::new(
+                "Authorization",
+                format!("Basic {}", base64::encode("confidential:wrong")),
+            ))
+            .body(body.clone())
+            .dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+
+        let response = client
+            .post("/okt/token")
+            .header(ContentType::Form)
+            .header(Header::new(
+                "Authorization",
+                format!("Basic {}", base64::encode("confidential:secret")),
+            ))
+            .body(body)
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+    }
+
+    #[test]
+    fn unauthenticated_code_replay_keeps_tokens() {
+        let client = Client::tracked(rocket()).expect("valid rocket instance");
+        let code = issue_code(&client, "confidential", "https://example.com");
+        let response = client
+            .post("/okt/token")
+            .header(ContentType::Form)
+            .header(Header::new(
+                "Authorization",
+                format!("Basic {}", base64::encode("confidential:secret")),
+            ))
+            .body(format!(
+                "grant_type=authorization_code&code={}&redirect_uri=https://example.com",
+                code
+            ))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let token = response.into_json::<Value>().unwrap();
+        let access_token = token.get("access_token").unwrap().as_str().unwrap();
+
+        let response = client.get(format!("/okt/token?code={}", code)).dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+        assert_eq!(error(response), "invalid_client");
+        let response = client
+            .post("/okt/token")
+            .header(ContentType::Form)
+            .body(format!(
+                "grant_type=authorization_code&code={}&redirect_uri=https://example.com&client_id=foo",
+                code
+            ))
+            .dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+
+        let store = client.rocket().state::<Store>().unwrap();
+        assert!(block_on(store.get_session(access_token)).unwrap().is_some());
+        assert!(block_on(store.get_code(&code)).unwrap().is_some());
     }
 }
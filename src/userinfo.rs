@@ -1,8 +1,11 @@
-use crate::claims::{Claims, ClaimsMutex};
+use crate::claims::Claims;
+use crate::config::{get_client, Config};
+use crate::jwe::encrypt;
+use crate::storage::Store;
+use chrono::Utc;
 use openidconnect::{core::CoreGenderClaim, UserInfoClaims};
-use rocket::http::Status;
+use rocket::http::{ContentType, Status};
 use rocket::request::{FromRequest, Outcome, Request};
-use rocket::response::status::NotFound;
 use rocket::serde::json::Json;
 use rocket::State;
 
@@ -26,34 +29,58 @@ impl<'r> FromRequest<'r> for Bearer {
     }
 }
 
+/// The claims, or for clients with `userinfo_encrypted_response_alg` the claims encrypted as
+/// `application/jwt`.
+#[derive(Responder)]
+pub enum UserInfoResponse {
+    Claims(Json<Box<UserInfoClaims<Claims, CoreGenderClaim>>>),
+    Encrypted((ContentType, String)),
+}
+
 #[get("/userinfo")]
 pub async fn default_userinfo_endpoint(
-    claims: &State<ClaimsMutex>,
+    config: &State<Config>,
+    store: &State<Store>,
     bearer: Bearer,
-) -> Result<Json<UserInfoClaims<Claims, CoreGenderClaim>>, NotFound<String>> {
-    userinfo_endpoint(claims, bearer, "default".into()).await
+) -> Result<UserInfoResponse, (Status, String)> {
+    userinfo_endpoint(config, store, bearer, "default".into()).await
 }
 
 #[allow(unused_variables)]
 #[get("/<realm>/userinfo")]
 pub async fn userinfo_endpoint(
-    claims: &State<ClaimsMutex>,
+    config: &State<Config>,
+    store: &State<Store>,
     bearer: Bearer,
     realm: String,
-) -> Result<Json<UserInfoClaims<Claims, CoreGenderClaim>>, NotFound<String>> {
-    println!("{:?}", bearer);
-
+) -> Result<UserInfoResponse, (Status, String)> {
     let access_token = bearer.0;
 
-    let locked = claims.standard_claims.lock().unwrap();
-    let standard_claims = locked.get(&access_token).unwrap();
-
-    let locked = claims.additional_claims.lock().unwrap();
-    let additional_claims = locked.get(&access_token).unwrap();
+    let session = store
+        .get_session(&access_token)
+        .await
+        .map_err(|err| (Status::InternalServerError, err))?
+        .ok_or_else(|| (Status::NotFound, "Invalid Token".to_string()))?;
 
-    let userinfo_claims = UserInfoClaims::new(standard_claims.clone(), additional_claims.clone());
+    if session.grant.expires_at <= Utc::now() {
+        return Err((Status::Unauthorized, "invalid_token".to_string()));
+    }
 
-    Ok(Json(userinfo_claims))
+    let userinfo_claims = UserInfoClaims::new(session.standard_claims, session.additional_claims);
+
+    let client = get_client(config, &session.grant.client_id);
+    match client.and_then(|client| Some((client, client.userinfo_encryption()?))) {
+        Some((client, (alg, enc))) => {
+            let claims = serde_json::to_vec(&userinfo_claims).unwrap();
+            let jwe = encrypt(client, alg, &enc, None, &claims)
+                .map_err(|err| (Status::InternalServerError, err))?;
+            Ok(UserInfoResponse::Encrypted((
+                ContentType::new("application", "jwt"),
+                jwe,
+            )))
+        }
+        None => Ok(UserInfoResponse::Claims(Json(Box::new(userinfo_claims)))),
+    }
 }
 
 #[options("/userinfo")]
@@ -66,7 +93,10 @@ pub async fn options_userinfo_endpoint(realm: String) {}
 #[cfg(test)]
 mod tests {
     use crate::rocket;
-    use rocket::http::{Header, Status};
+    use crate::tests::jwe::{decrypt, header};
+    use crate::tests::{issue_challenge, issue_code, update_session};
+    use chrono::{Duration, Utc};
+    use rocket::http::{ContentType, Header, Status};
     use rocket::local::blocking::Client;
     use serde_json::Value;
     use std::collections::HashMap;
@@ -81,11 +111,12 @@ mod tests {
         let signature = "0x87b709d1e84aab056cf089af31e8d7c891d6f363663ff3eeb4bbb4c4e0602b2e3edf117fe548626b8d83e3b2c530cb55e2baff29ca54dbd495bb45764d9aa44c1c".to_string();
 
         let client = Client::tracked(rocket()).expect("valid rocket instance");
+        issue_challenge(&client, &nonce);
 
         let response = client
             .get(format!(
-                "/authorize?client_id={}&realm=okt&redirect_uri=https://example.com&nonce={}&contract={}&account={}&signature={}",
-                client_id, nonce, contract, account, signature
+                "/authorize?client_id={}&realm=okt&redirect_uri=https://example.com&nonce={}&contract={}&account={}&signature={}&challenge={}",
+                client_id, nonce, contract, account, signature, nonce
             ))
             .dispatch();
         assert_eq!(response.status(), Status::TemporaryRedirect);
@@ -102,12 +133,9 @@ mod tests {
 
         assert!(params.get("code").is_some());
         let code = params.get("code").unwrap();
-        let response = client.get(format!("/token?code={}", code)).dispatch();
-        assert_eq!(response.status(), Status::Ok);
         let response = client.get(format!("/okt/token?code={}", code)).dispatch();
         assert_eq!(response.status(), Status::Ok);
         let token = response.into_json::<Value>().unwrap();
-        println!("{:?}", token);
         let access_token = token.get("access_token");
         assert!(access_token.is_some());
         let access_token = access_token.unwrap().as_str().unwrap().to_string();
@@ -115,7 +143,7 @@ mod tests {
         let response = client
             .get(format!("/token?code={}", "invalid".to_string()))
             .dispatch();
-        assert_eq!(response.status(), Status::NotFound);
+        assert_eq!(response.status(), Status::BadRequest);
 
         let response = client.get("/userinfo").dispatch();
         assert_eq!(response.status(), Status::BadRequest);
@@ -129,9 +157,91 @@ mod tests {
             .dispatch();
         assert_ne!(response.status(), Status::BadRequest);
         let userinfo = response.into_json::<Value>().unwrap();
-        
+
         assert_eq!(userinfo.get("account").unwrap().as_str().unwrap(), account);
-        assert_eq!(userinfo.get("contract").unwrap().as_str().unwrap(), contract);
+        assert_eq!(
+            userinfo.get("contract").unwrap().as_str().unwrap(),
+            contract
+        );
         assert_eq!(userinfo.get("nonce").unwrap().as_str().unwrap(), nonce);
     }
+
+    #[test]
+    fn userinfo_of_revoked_token() {
+        let client = Client::tracked(rocket()).expect("valid rocket instance");
+        let code = issue_code(&client, "foo", "https://example.com");
+
+        let response = client.get(format!("/okt/token?code={}", code)).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let token = response.into_json::<Value>().unwrap();
+        let access_token = token.get("access_token").unwrap().as_str().unwrap();
+        let bearer = Header::new("Authorization", format!("Bearer {}", access_token));
+
+        let response = client
+            .get("/okt/userinfo")
+            .header(bearer.clone())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+
+        let response = client.get(format!("/okt/token?code={}", code)).dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+
+        let response = client.get("/okt/userinfo").header(bearer).dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+    }
+
+    #[test]
+    fn userinfo_of_expired_token() {
+        let client = Client::tracked(rocket()).expect("valid rocket instance");
+        let code = issue_code(&client, "foo", "https://example.com");
+
+        let response = client.get(format!("/okt/token?code={}", code)).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let token = response.into_json::<Value>().unwrap();
+        let access_token = token.get("access_token").unwrap().as_str().unwrap();
+        let bearer = Header::new("Authorization", format!("Bearer {}", access_token));
+
+        update_session(&client, access_token, |session| {
+            session.grant.expires_at = Utc::now() - Duration::seconds(1);
+        });
+        let response = client.get("/okt/userinfo").header(bearer).dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+        assert_eq!(response.into_string().unwrap(), "invalid_token");
+    }
+    #[test]
+    fn encrypted_userinfo() {
+        let client = Client::tracked(rocket()).expect("valid rocket instance");
+        let code = issue_code(&client, "encrypted", "https://example.com");
+
+        let response = client.get(format!("/okt/token?code={}", code)).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let token = response.into_json::<Value>().unwrap();
+        let id_token = token.get("id_token").unwrap().as_str().unwrap();
+        assert_eq!(id_token.split('.').count(), 5);
+        assert_eq!(header(id_token).get("enc").unwrap(), "A256GCM");
+        let signed_id_token = String::from_utf8(decrypt(id_token)).unwrap();
+        assert_eq!(signed_id_token.split('.').count(), 3);
+
+        let access_token = token.get("access_token").unwrap().as_str().unwrap();
+        let response = client
+            .get("/okt/userinfo")
+            .header(Header::new(
+                "Authorization",
+                format!("Bearer {}", access_token),
+            ))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(
+            response.content_type(),
+            Some(ContentType::new("application", "jwt"))
+        );
+        let jwe = response.into_string().unwrap();
+        assert_eq!(header(&jwe).get("alg").unwrap(), "RSA-OAEP-256");
+        assert_eq!(header(&jwe).get("enc").unwrap(), "A128GCM");
+        let claims: Value = serde_json::from_slice(&decrypt(&jwe)).unwrap();
+        assert_eq!(
+            claims.get("sub").unwrap(),
+            "0x9c9e8eabd947658bdb713e0d3ebfe56860abdb8d"
+        );
+    }
 }
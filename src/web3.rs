@@ -1,56 +1,252 @@
+use serde::{Deserialize, Serialize};
 use std::str::FromStr;
 use web3::signing::{keccak256, recover};
 
 use web3::{
     contract::{Contract, Error, Options},
-    types::{Address, U256},
+    types::{Address, Bytes, H256, U256},
 };
 
-pub fn validate_signature(account: String, nonce: String, signature: String) -> bool {
-    let message = eth_message(format!("{};{}", account, nonce));
+#[derive(Debug, Default, PartialEq, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenStandard {
+    #[default]
+    Erc721,
+    Erc1155,
+    Erc20,
+}
+
+impl FromStr for TokenStandard {
+    type Err = String;
+
+    fn from_str(token_standard: &str) -> Result<Self, Self::Err> {
+        match token_standard.to_lowercase().as_str() {
+            "erc721" => Ok(TokenStandard::Erc721),
+            "erc1155" => Ok(TokenStandard::Erc1155),
+            "erc20" => Ok(TokenStandard::Erc20),
+            _ => Err("unsupported token standard".to_string()),
+        }
+    }
+}
+
+const EIP1271_MAGIC_VALUE: [u8; 4] = [0x16, 0x26, 0xba, 0x7e];
+
+pub async fn validate_signature(
+    account: String,
+    message: [u8; 32],
+    signature: String,
+    node_provider: String,
+) -> bool {
     if signature.len() < 2 {
         return false;
     }
-    let signature = (&signature[2..]).to_string();
-    let signature = hex::decode(signature);
+    let signature = hex::decode(&signature[2..]);
     if signature.is_err() {
         return false;
     }
     let signature = signature.unwrap();
-    let pubkey0 = recover(&message, &signature[..64], 0);
-    let pubkey1 = recover(&message, &signature[..64], 1);
-    let pubkey0 = format!("{:02X?}", pubkey0.unwrap());
-    let pubkey1 = format!("{:02X?}", pubkey1.unwrap());
-    pubkey0 == account || pubkey1 == account
+    if is_signer(&account, &message, &signature) {
+        return true;
+    }
+    is_valid_contract_signature(account, message, signature, node_provider)
+        .await
+        .unwrap_or(false)
 }
 
-pub async fn is_nft_owner_of(
-    contract_address: String,
-    owner_address: String,
+pub fn is_signer(account: &str, message: &[u8; 32], signature: &[u8]) -> bool {
+    if signature.len() < 64 {
+        return false;
+    }
+    let account = account.to_lowercase();
+    (0..2).any(
+        |recovery_id| match recover(message, &signature[..64], recovery_id) {
+            Ok(pubkey) => format!("{:02X?}", pubkey) == account,
+            Err(_) => false,
+        },
+    )
+}
+
+pub async fn is_valid_contract_signature(
+    account: String,
+    message: [u8; 32],
+    signature: Vec<u8>,
     node_provider: String,
 ) -> web3::Result<bool> {
-    let transport = web3::transports::Http::new(&node_provider).unwrap();
+    let account = Address::from_str(&account);
+    if account.is_err() {
+        return Ok(false);
+    }
+    let transport = web3::transports::Http::new(&node_provider)?;
     let web3 = web3::Web3::new(transport);
-    let abi = include_bytes!("erc721.json");
-    let contract_address = Address::from_str(&contract_address).unwrap();
-    let contract = Contract::from_json(web3.eth(), contract_address, abi).unwrap();
+    let abi = include_bytes!("erc1271.json");
+    let contract = Contract::from_json(web3.eth(), account.unwrap(), abi).unwrap();
 
-    let owner_address = Address::from_str(&owner_address).unwrap();
-    let balance: Result<U256, Error> = contract
+    let magic_value: Result<[u8; 4], Error> = contract
         .query(
-            "balanceOf",
-            (owner_address,),
+            "isValidSignature",
+            (H256::from(message), Bytes(signature)),
             None,
             Options::default(),
             None,
         )
         .await;
-    match balance {
-        Ok(balance) => Ok(balance > U256::from(0)),
+    match magic_value {
+        Ok(magic_value) => Ok(magic_value == EIP1271_MAGIC_VALUE),
         Err(e) => Err(web3::Error::InvalidResponse(e.to_string())),
     }
 }
 
+/// A condition on the tokens an account has to hold on a contract.
+#[derive(Debug, Default, PartialEq, Clone, Serialize, Deserialize)]
+pub struct OwnershipCondition {
+    pub contract: String,
+    pub token_standard: TokenStandard,
+    pub token_ids: Vec<U256>,
+    pub token_id: Option<U256>,
+    pub min_balance: Option<String>,
+}
+
+#[cfg(test)]
+impl OwnershipCondition {
+    pub fn new(contract: String) -> Self {
+        OwnershipCondition {
+            contract,
+            ..Default::default()
+        }
+    }
+}
+
+/// The balance observed while evaluating an `OwnershipCondition`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Ownership {
+    pub is_owner: bool,
+    pub balance: U256,
+}
+
+pub async fn is_nft_owner_of(
+    condition: &OwnershipCondition,
+    owner_address: String,
+    node_provider: String,
+) -> web3::Result<Ownership> {
+    let transport = web3::transports::Http::new(&node_provider)?;
+    let web3 = web3::Web3::new(transport);
+    let abi: &[u8] = match condition.token_standard {
+        TokenStandard::Erc721 => include_bytes!("erc721.json"),
+        TokenStandard::Erc1155 => include_bytes!("erc1155.json"),
+        TokenStandard::Erc20 => include_bytes!("erc20.json"),
+    };
+    let contract_address = Address::from_str(&condition.contract)
+        .map_err(|_| web3::Error::Decoder("invalid contract address".to_string()))?;
+    let contract = Contract::from_json(web3.eth(), contract_address, abi).unwrap();
+    let owner_address = Address::from_str(&owner_address)
+        .map_err(|_| web3::Error::Decoder("invalid owner address".to_string()))?;
+
+    let balance: Result<U256, Error> = match (condition.token_standard, condition.token_id) {
+        (TokenStandard::Erc721, Some(token_id)) => contract
+            .query("ownerOf", (token_id,), None, Options::default(), None)
+            .await
+            .map(|owner: Address| U256::from((owner == owner_address) as u8)),
+        (TokenStandard::Erc1155, token_id) => {
+            let token_ids: Vec<U256> = condition
+                .token_ids
+                .iter()
+                .cloned()
+                .chain(token_id)
+                .collect();
+            match token_ids.len() {
+                1 => {
+                    contract
+                        .query(
+                            "balanceOf",
+                            (owner_address, token_ids[0]),
+                            None,
+                            Options::default(),
+                            None,
+                        )
+                        .await
+                }
+                _ => contract
+                    .query(
+                        "balanceOfBatch",
+                        (vec![owner_address; token_ids.len()], token_ids),
+                        None,
+                        Options::default(),
+                        None,
+                    )
+                    .await
+                    .map(|balances: Vec<U256>| {
+                        balances
+                            .iter()
+                            .fold(U256::from(0), |sum, balance| sum.saturating_add(*balance))
+                    }),
+            }
+        }
+        _ => {
+            contract
+                .query(
+                    "balanceOf",
+                    (owner_address,),
+                    None,
+                    Options::default(),
+                    None,
+                )
+                .await
+        }
+    };
+    let balance = balance.map_err(|e| web3::Error::InvalidResponse(e.to_string()))?;
+
+    let decimals: u8 = match condition.token_standard {
+        TokenStandard::Erc20 => contract
+            .query("decimals", (), None, Options::default(), None)
+            .await
+            .map_err(|e| web3::Error::InvalidResponse(e.to_string()))?,
+        _ => 0,
+    };
+    let min_balance = match &condition.min_balance {
+        Some(min_balance) => parse_units(min_balance, decimals).map_err(web3::Error::Decoder)?,
+        None => U256::from(1),
+    };
+
+    Ok(Ownership {
+        is_owner: balance >= min_balance && !balance.is_zero(),
+        balance,
+    })
+}
+
+/// Converts a decimal amount like `100` or `0.5` into base units of a token with `decimals`.
+pub fn parse_units(amount: &str, decimals: u8) -> Result<U256, String> {
+    let invalid = || format!("invalid amount {}", amount);
+    let (integer, fraction) = match amount.trim().split_once('.') {
+        Some((integer, fraction)) => (integer, fraction.trim_end_matches('0')),
+        None => (amount.trim(), ""),
+    };
+    if integer.is_empty() || fraction.len() > decimals as usize {
+        return Err(invalid());
+    }
+    if !(integer.chars().chain(fraction.chars())).all(|c| c.is_ascii_digit()) {
+        return Err(invalid());
+    }
+    U256::from_dec_str(&format!(
+        "{}{}{}",
+        integer,
+        fraction,
+        "0".repeat(decimals as usize - fraction.len())
+    ))
+    .map_err(|_| invalid())
+}
+
+pub fn parse_token_ids(token_ids: &str) -> Result<Vec<U256>, String> {
+    token_ids
+        .split(',')
+        .map(|token_id| match token_id.trim() {
+            "" => Err("invalid token id".to_string()),
+            token_id => {
+                U256::from_dec_str(token_id).map_err(|_| format!("invalid token id {}", token_id))
+            }
+        })
+        .collect()
+}
+
 pub fn eth_message(message: String) -> [u8; 32] {
     keccak256(
         format!(
@@ -63,6 +259,10 @@ pub fn eth_message(message: String) -> [u8; 32] {
     )
 }
 
+pub fn typed_data_message(domain_separator: [u8; 32], struct_hash: [u8; 32]) -> [u8; 32] {
+    keccak256(&[&[0x19, 0x01], &domain_separator[..], &struct_hash[..]].concat())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -80,19 +280,18 @@ mod tests {
         )
     }
 
-    #[test]
-    fn test_wrong_signature() {
+    #[tokio::test]
+    async fn test_wrong_signature() {
         let account = "".to_string();
-        let nonce = "".to_string();
+        let message = eth_message("".to_string());
+        let node_provider = "http://localhost:8545".to_string();
         let signature = "".to_string();
-        assert!(!validate_signature(
-            account.clone(),
-            nonce.clone(),
-            signature
-        ));
+        assert!(
+            !validate_signature(account.clone(), message, signature, node_provider.clone()).await
+        );
 
         let signature = "foobar".to_string();
-        assert!(!validate_signature(account, nonce, signature));
+        assert!(!validate_signature(account, message, signature, node_provider).await);
     }
 
     #[test]
@@ -101,7 +300,6 @@ mod tests {
         let message = "0x63f9a92d8d61b48a9fff8d58080425a3012d05c8igwyk4r1o7o".to_string();
         let message = eth_message(message);
         let signature = hex::decode("382a3e04daf88f322730f6a2972475fc5646ea8c4a7f3b5e83a90b10ba08a7364cd2f55348f2b6d210fbed7fc485abf19ecb2f3967e410d6349dd7dd1d4487751b").unwrap();
-        println!("{} {:?} {:?}", account, message, signature);
         let pubkey = recover(&message, &signature[..64], 0);
         assert!(pubkey.is_ok());
         let pubkey = pubkey.unwrap();
@@ -109,13 +307,94 @@ mod tests {
         assert_eq!(account, pubkey)
     }
 
-    #[test]
-    fn test_validate() {
+    #[tokio::test]
+    async fn test_validate() {
         let account = "0x9c9e8eabd947658bdb713e0d3ebfe56860abdb8d".to_string();
         let nonce = "dotzxrenodo".to_string();
         let signature = "0x87b709d1e84aab056cf089af31e8d7c891d6f363663ff3eeb4bbb4c4e0602b2e3edf117fe548626b8d83e3b2c530cb55e2baff29ca54dbd495bb45764d9aa44c1c".to_string();
+        let message = eth_message(format!("{};{}", account, nonce));
+
+        assert!(
+            validate_signature(
+                account,
+                message,
+                signature,
+                "http://localhost:8545".to_string()
+            )
+            .await
+        );
+    }
+
+    #[test]
+    fn test_is_signer() {
+        let account = "0x9C9E8EABD947658BDB713E0D3EBFE56860ABDB8D";
+        let message =
+            eth_message("0x9c9e8eabd947658bdb713e0d3ebfe56860abdb8d;dotzxrenodo".to_string());
+        let signature = hex::decode("87b709d1e84aab056cf089af31e8d7c891d6f363663ff3eeb4bbb4c4e0602b2e3edf117fe548626b8d83e3b2c530cb55e2baff29ca54dbd495bb45764d9aa44c1c").unwrap();
+        assert!(is_signer(account, &message, &signature));
+        assert!(!is_signer(account, &message, &signature[..32]));
+        assert!(!is_signer("0x0", &message, &signature));
+    }
+
+    #[test]
+    fn test_token_standard() {
+        assert_eq!(TokenStandard::from_str("ERC721"), Ok(TokenStandard::Erc721));
+        assert_eq!(
+            TokenStandard::from_str("erc1155"),
+            Ok(TokenStandard::Erc1155)
+        );
+        assert!(TokenStandard::from_str("erc42").is_err());
+    }
+
+    #[test]
+    fn test_parse_token_ids() {
+        assert_eq!(parse_token_ids("1"), Ok(vec![U256::from(1)]));
+        assert_eq!(
+            parse_token_ids("1, 42"),
+            Ok(vec![U256::from(1), U256::from(42)])
+        );
+        assert!(parse_token_ids("").is_err());
+        assert!(parse_token_ids("0x1").is_err());
+    }
+
+    #[test]
+    fn test_parse_units() {
+        assert_eq!(parse_units("1", 0), Ok(U256::from(1)));
+        assert_eq!(parse_units("100", 2), Ok(U256::from(10000)));
+        assert_eq!(parse_units("0.5", 1), Ok(U256::from(5)));
+        assert_eq!(parse_units("1.50", 1), Ok(U256::from(15)));
+        assert_eq!(
+            parse_units("100", 18),
+            Ok(U256::from_dec_str("100000000000000000000").unwrap())
+        );
+        assert!(parse_units("0.5", 0).is_err());
+        assert!(parse_units("", 18).is_err());
+        assert!(parse_units(".5", 18).is_err());
+        assert!(parse_units("-1", 18).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_invalid_contract() {
+        assert!(is_nft_owner_of(
+            &OwnershipCondition::new("foo".to_string()),
+            "0x9c9e8eabd947658bdb713e0d3ebfe56860abdb8d".to_string(),
+            "http://localhost:8545".to_string()
+        )
+        .await
+        .is_err());
+    }
 
-        assert!(validate_signature(account, nonce, signature));
+    #[tokio::test]
+    async fn test_contract_signature_of_invalid_account() {
+        let message = eth_message("".to_string());
+        assert!(!is_valid_contract_signature(
+            "no account".to_string(),
+            message,
+            vec![],
+            "http://localhost:8545".to_string()
+        )
+        .await
+        .unwrap());
     }
 
     #[ignore]
@@ -127,13 +406,16 @@ mod tests {
 
         let ck_token_addr = "0x06012c8cf97BEaD5deAe237070F9587f8E7A266d".to_string();
         let owner = "0xb1690c08e213a35ed9bab7b318de14420fb57d8c".to_string();
-        assert!(is_nft_owner_of(
-            ck_token_addr,
-            owner,
-            config.node_provider["default"].clone()
-        )
-        .await
-        .unwrap());
+        assert!(
+            is_nft_owner_of(
+                &OwnershipCondition::new(ck_token_addr),
+                owner,
+                config.node_provider["default"].clone()
+            )
+            .await
+            .unwrap()
+            .is_owner
+        );
     }
 
     #[ignore]
@@ -146,9 +428,14 @@ mod tests {
         let nft_addr = "0x420d2a6E87D87992EB01e5BFe762B3F437dBfD85".to_string();
         let owner = "0x8f4F7365981A73Dd61d5aa74cCe4C0F251f67faC".to_string();
         assert!(
-            is_nft_owner_of(nft_addr, owner, config.node_provider["default"].clone())
-                .await
-                .unwrap()
+            is_nft_owner_of(
+                &OwnershipCondition::new(nft_addr),
+                owner,
+                config.node_provider["default"].clone()
+            )
+            .await
+            .unwrap()
+            .is_owner
         );
     }
 
@@ -161,9 +448,14 @@ mod tests {
         let nft_addr = "0x886B6781CD7dF75d8440Aba84216b2671AEFf9A4".to_string();
         let owner = "0x9c9E8eAbD947658bDb713E0d3eBfe56860abdb8D".to_string();
         assert!(
-            is_nft_owner_of(nft_addr, owner, config.node_provider["okt"].clone())
-                .await
-                .unwrap()
+            is_nft_owner_of(
+                &OwnershipCondition::new(nft_addr),
+                owner,
+                config.node_provider["okt"].clone()
+            )
+            .await
+            .unwrap()
+            .is_owner
         );
     }
 }
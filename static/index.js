@@ -19,7 +19,7 @@ async function sign_message() {
     account,
     nonce,
   };
-  const message = "" + account + ";" + encodeURIComponent(nonce);
+  const message = siweMessage(account);
   console.log(message);
   var signature = await ethereum.request({
     method: "personal_sign",
@@ -35,6 +35,7 @@ async function sign_message() {
   ));
   urlParams.set("chain_id", chainId);
   urlParams.set("signature", encodeURIComponent(signature));
+  urlParams.set("message", message);
 
 
   const query = `${urlParams.toString()}`;
@@ -42,6 +43,103 @@ async function sign_message() {
   window.open("/authorize?" + query, '_self');
 }
 
+async function sign_typed_data() {
+  if (!isAddress(nft)) {
+    showError("The login link has no valid contract address, please start the login again.");
+    return;
+  }
+  const accounts = await ethereum.request({ method: "eth_requestAccounts" });
+  const account = accounts[0];
+  const expiry = Math.floor(Date.now() / 1000) + 5 * 60;
+
+  const typedData = loginTypedData(account, expiry);
+  console.log(typedData);
+  var signature = await ethereum.request({
+    method: "eth_signTypedData_v4",
+    params: [account, JSON.stringify(typedData)],
+  });
+  console.log(signature);
+  const urlParams = new URLSearchParams(queryString);
+  urlParams.set("account", encodeURIComponent(account));
+  urlParams.set("chain_id", chainId);
+  urlParams.set("signature", encodeURIComponent(signature));
+  urlParams.set("expiry", expiry);
+
+  const query = `${urlParams.toString()}`;
+  console.log(query);
+  window.open("/authorize?" + query, "_self");
+}
+
+function loginTypedData(account, expiry) {
+  return {
+    types: {
+      EIP712Domain: [
+        { name: "name", type: "string" },
+        { name: "version", type: "string" },
+        { name: "chainId", type: "uint256" },
+      ],
+      Login: [
+        { name: "account", type: "address" },
+        { name: "nonce", type: "string" },
+        { name: "client_id", type: "string" },
+        { name: "contract", type: "address" },
+        { name: "expiry", type: "uint256" },
+      ],
+    },
+    primaryType: "Login",
+    domain: {
+      name: window.location.host,
+      version: "1",
+      chainId,
+    },
+    message: {
+      account,
+      nonce: challenge,
+      client_id,
+      contract: nft,
+      expiry,
+    },
+  };
+}
+
+function isAddress(value) {
+  return /^0x[0-9a-fA-F]{40}$/.test(value || "");
+}
+
+function showError(message) {
+  const loginError = document.getElementById("login_error");
+  loginError.textContent = message;
+  loginError.hidden = false;
+}
+
+function siweMessage(account) {
+  const issuedAt = new Date();
+  const expirationTime = new Date(issuedAt.getTime() + 5 * 60 * 1000);
+  return (
+    window.location.host +
+    " wants you to sign in with your Ethereum account:\n" +
+    Web3.utils.toChecksumAddress(account) +
+    "\n\n" +
+    "Log in with your NFT Account" +
+    "\n\n" +
+    "URI: " +
+    window.location.origin +
+    "\n" +
+    "Version: 1\n" +
+    "Chain ID: " +
+    chainId +
+    "\n" +
+    "Nonce: " +
+    challenge +
+    "\n" +
+    "Issued At: " +
+    issuedAt.toISOString() +
+    "\n" +
+    "Expiration Time: " +
+    expirationTime.toISOString()
+  );
+}
+
 function chainDescription(chain) {
   var name = chain || `${parseInt(ethereum.chainId)}`;
   return (
@@ -57,6 +155,8 @@ function nftDescription(nft) {
 
 var sign_message_button = document.getElementById("sign_message_button");
 sign_message_button.addEventListener("click", sign_message);
+var sign_typed_data_button = document.getElementById("sign_typed_data_button");
+sign_typed_data_button.addEventListener("click", sign_typed_data);
 
 const queryString = window.location.search;
 const chainId = parseInt(ethereum.chainId);
@@ -64,9 +164,11 @@ console.log(chainId);
 console.log(queryString);
 const urlParams = new URLSearchParams(queryString);
 const nonce = urlParams.get("nonce");
+const challenge = urlParams.get("challenge");
 const redirect_uri = urlParams.get("redirect_uri");
+const client_id = urlParams.get("client_id");
 const chain = urlParams.get("chain");
-const nft = urlParams.get("contract") || urlParams.get("client_id");
+const nft = urlParams.get("contract");
 console.log(nonce);
 
 document.getElementById("chain-description").innerHTML =